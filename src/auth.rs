@@ -0,0 +1,515 @@
+//! The Basic authentication scheme, and answering the challenges of the
+//! Digest scheme as described in RFC 7616.
+
+use crate::request::{Body, Request};
+use crate::url::Parts;
+use crate::Response;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The username and password a Digest challenge is answered with.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// A Digest challenge of a WWW-Authenticate field.
+#[derive(Debug, PartialEq, Eq)]
+struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    session: bool,
+    qop: Vec<String>,
+    userhash: bool,
+    /// Whether the nonce of the previous challenge was only rejected for
+    /// being too old.
+    stale: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Credentials {
+    pub(crate) fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl Algorithm {
+    fn hash<D: AsRef<[u8]>>(self, data: D) -> String {
+        let digest = match self {
+            Algorithm::Md5 => Md5::digest(data).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+        };
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// The value of an Authorization field for the Basic scheme.
+pub(crate) fn basic(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", username, password))
+    )
+}
+
+/// The Digest challenge last answered for each origin, so that later
+/// requests to it answer the challenge in advance instead of being
+/// challenged again.
+#[derive(Debug, Default)]
+pub(crate) struct DigestCache(Mutex<HashMap<Origin, Cached>>);
+
+/// `(scheme, host, port)` as returned by `Parts::origin`.
+type Origin = (String, String, Option<u16>);
+
+/// A cached challenge and the number of requests which answered it.
+#[derive(Debug)]
+struct Cached {
+    challenge: Challenge,
+    nc: u32,
+}
+
+impl DigestCache {
+    /// The Authorization field answering the challenge cached for the origin
+    /// of `request` in advance, `None` if there is none.
+    ///
+    /// `request` is the head of the request, `body` its body as for
+    /// `redirect::next_request`.
+    pub(crate) fn authorization(
+        &self,
+        request: &Request,
+        body: &Option<Option<Body>>,
+    ) -> Option<String> {
+        let credentials = request.digest_auth.as_ref()?;
+        if request.headers.contains_key("Authorization") {
+            return None;
+        }
+        let origin = Parts::parse(&request.url).origin()?;
+        let mut cache = self.0.lock().unwrap();
+        let cached = cache.get_mut(&origin)?;
+        let body = match body {
+            Some(Some(body)) => Some(body.as_bytes().unwrap_or_default()),
+            // A streamed body cannot be hashed for integrity protection
+            Some(None) if !cached.challenge.qop.iter().any(|qop| qop == "auth") => return None,
+            _ => None,
+        };
+        cached.nc += 1;
+        answer(
+            credentials,
+            &cached.challenge,
+            request.method.as_str(),
+            &request_uri(&request.url),
+            body,
+            cached.nc,
+            &cnonce(),
+        )
+    }
+
+    /// The Authorization field answering the Digest challenge of `response`
+    /// to `request`, `None` if there is none to answer. The challenge is
+    /// cached for the origin of `request`.
+    ///
+    /// Requests which set an Authorization field themselves are left alone,
+    /// as are those whose body cannot be sent again. If `preempted`, the
+    /// request already answered a cached challenge, and a new one is only
+    /// answered if the server considers the old nonce stale, as otherwise
+    /// the credentials were rejected.
+    pub(crate) fn answer(
+        &self,
+        request: &Request,
+        body: &Option<Option<Body>>,
+        response: &Response,
+        preempted: bool,
+    ) -> Option<String> {
+        let credentials = request.digest_auth.as_ref()?;
+        if response.status_code() != 401 || request.headers.contains_key("Authorization") {
+            return None;
+        }
+        // The strongest algorithm the server offers
+        let challenge = response
+            .headers()
+            .get_all("WWW-Authenticate")
+            .filter_map(|value| value.to_str())
+            .flat_map(parse_challenges)
+            .max_by_key(|challenge| challenge.algorithm)?;
+        if preempted && !challenge.stale {
+            return None;
+        }
+
+        // A streamed body cannot be sent again, only later requests answer
+        // the challenge
+        let streamed = matches!(body, Some(None));
+        let body = body
+            .as_ref()
+            .and_then(Option::as_ref)
+            .map(|body| body.as_bytes().unwrap_or_default());
+        let authorization = if streamed {
+            None
+        } else {
+            answer(
+                credentials,
+                &challenge,
+                request.method.as_str(),
+                &request_uri(&request.url),
+                body,
+                1,
+                &cnonce(),
+            )
+        };
+        // Without a quality of protection there is no nonce count, and the
+        // server could not tell a reused nonce from a replayed request
+        if let (false, Some(origin)) = (
+            challenge.qop.is_empty(),
+            Parts::parse(&request.url).origin(),
+        ) {
+            let cached = Cached { challenge, nc: 1 };
+            self.0.lock().unwrap().insert(origin, cached);
+        }
+        authorization
+    }
+}
+
+/// The path and query of `url`, which a Digest answer refers to.
+fn request_uri(url: &str) -> String {
+    let parts = Parts::parse(url);
+    let uri = match parts.query {
+        Some(query) => format!("{}?{}", parts.path, query),
+        None => parts.path.to_string(),
+    };
+    if uri.starts_with('/') {
+        uri
+    } else {
+        format!("/{}", uri)
+    }
+}
+
+/// The Digest challenges of a WWW-Authenticate field value, skipping those
+/// with an unsupported algorithm.
+fn parse_challenges(value: &str) -> Vec<Challenge> {
+    let mut schemes: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        let end = rest
+            .find(|c: char| c == '=' || c == ',' || c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        if end == 0 {
+            break;
+        }
+        let token = &rest[..end];
+        rest = rest[end..].trim_start();
+        match rest.strip_prefix('=') {
+            Some(value) => {
+                let (value, remaining) = parse_value(value.trim_start());
+                rest = remaining;
+                if let Some((_, params)) = schemes.last_mut() {
+                    params.push((token.to_ascii_lowercase(), value));
+                }
+            }
+            None => schemes.push((token.to_string(), Vec::new())),
+        }
+    }
+
+    schemes
+        .into_iter()
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Digest"))
+        .filter_map(|(_, params)| {
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.clone())
+            };
+            let algorithm = param("algorithm").unwrap_or_else(|| "MD5".to_string());
+            let (algorithm, session) = match algorithm.to_ascii_uppercase().as_str() {
+                "MD5" => (Algorithm::Md5, false),
+                "MD5-SESS" => (Algorithm::Md5, true),
+                "SHA-256" => (Algorithm::Sha256, false),
+                "SHA-256-SESS" => (Algorithm::Sha256, true),
+                _ => return None,
+            };
+            Some(Challenge {
+                realm: param("realm").unwrap_or_default(),
+                nonce: param("nonce")?,
+                opaque: param("opaque"),
+                algorithm,
+                session,
+                qop: param("qop")
+                    .map(|qop| {
+                        qop.split(',')
+                            .map(|qop| qop.trim().to_ascii_lowercase())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                userhash: param("userhash").is_some_and(|value| value.eq_ignore_ascii_case("true")),
+                stale: param("stale").is_some_and(|value| value.eq_ignore_ascii_case("true")),
+            })
+        })
+        .collect()
+}
+
+/// A token or quoted string at the start of `input`, and what follows it.
+fn parse_value(input: &str) -> (String, &str) {
+    let quoted = match input.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => {
+            let end = input.find(',').unwrap_or(input.len());
+            return (input[..end].trim_end().to_string(), &input[end..]);
+        }
+    };
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' => return (value, &quoted[pos + 1..]),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            _ => value.push(c),
+        }
+    }
+    // An unterminated string ends with the input
+    (value, "")
+}
+
+/// The Authorization field answering `challenge`, `None` if it requires an
+/// unsupported quality of protection.
+///
+/// `body` is only needed for integrity protection, which is used if the
+/// server does not offer authentication alone. `nc` counts the requests
+/// which answered the nonce so far, including this one.
+fn answer(
+    credentials: &Credentials,
+    challenge: &Challenge,
+    method: &str,
+    uri: &str,
+    body: Option<&[u8]>,
+    nc: u32,
+    cnonce: &str,
+) -> Option<String> {
+    let algorithm = challenge.algorithm;
+    let qop = if challenge.qop.is_empty() {
+        None
+    } else if challenge.qop.iter().any(|qop| qop == "auth") {
+        Some("auth")
+    } else if challenge.qop.iter().any(|qop| qop == "auth-int") {
+        Some("auth-int")
+    } else {
+        return None;
+    };
+    let nc = format!("{:08x}", nc);
+
+    let mut ha1 = algorithm.hash(format!(
+        "{}:{}:{}",
+        credentials.username, challenge.realm, credentials.password
+    ));
+    if challenge.session {
+        ha1 = algorithm.hash(format!("{}:{}:{}", ha1, challenge.nonce, cnonce));
+    }
+    let ha2 = match qop {
+        Some("auth-int") => {
+            let body = algorithm.hash(body.unwrap_or_default());
+            algorithm.hash(format!("{}:{}:{}", method, uri, body))
+        }
+        _ => algorithm.hash(format!("{}:{}", method, uri)),
+    };
+    let response = match qop {
+        Some(qop) => algorithm.hash(format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1, challenge.nonce, nc, cnonce, qop, ha2
+        )),
+        None => algorithm.hash(format!("{}:{}:{}", ha1, challenge.nonce, ha2)),
+    };
+
+    let username = if challenge.userhash {
+        algorithm.hash(format!("{}:{}", credentials.username, challenge.realm))
+    } else {
+        credentials.username.clone()
+    };
+    let mut authorization = format!(
+        "Digest username={}, realm={}, uri={}, algorithm={}{}, nonce={}",
+        quote(&username),
+        quote(&challenge.realm),
+        quote(uri),
+        match algorithm {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        },
+        if challenge.session { "-sess" } else { "" },
+        quote(&challenge.nonce)
+    );
+    if let Some(qop) = qop {
+        authorization.push_str(&format!(
+            ", nc={}, cnonce={}, qop={}",
+            nc,
+            quote(cnonce),
+            qop
+        ));
+    }
+    authorization.push_str(&format!(", response={}", quote(&response)));
+    if let Some(opaque) = &challenge.opaque {
+        authorization.push_str(&format!(", opaque={}", quote(opaque)));
+    }
+    if challenge.userhash {
+        authorization.push_str(", userhash=true");
+    }
+    Some(authorization)
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A client nonce which differs between calls.
+fn cnonce() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges() {
+        let challenges = parse_challenges(
+            "Basic realm=\"basic\", Digest realm=\"a \\\"b\\\"\", qop=\"auth, auth-int\", \
+             nonce=abc, algorithm=SHA-256-sess, userhash=true, stale=TRUE, Digest nonce=\"x\", \
+             algorithm=SHA-512, Digest nonce=\"y\", opaque=\"z\"",
+        );
+        assert_eq!(
+            challenges,
+            [
+                Challenge {
+                    realm: "a \"b\"".to_string(),
+                    nonce: "abc".to_string(),
+                    opaque: None,
+                    algorithm: Algorithm::Sha256,
+                    session: true,
+                    qop: vec!["auth".to_string(), "auth-int".to_string()],
+                    userhash: true,
+                    stale: true,
+                },
+                Challenge {
+                    realm: String::new(),
+                    nonce: "y".to_string(),
+                    opaque: Some("z".to_string()),
+                    algorithm: Algorithm::Md5,
+                    session: false,
+                    qop: Vec::new(),
+                    userhash: false,
+                    stale: false,
+                },
+            ]
+        );
+        assert!(parse_challenges("Bearer realm=\"api\"").is_empty());
+    }
+
+    #[test]
+    fn answers() {
+        // Example of RFC 7616, Section 3.9.1
+        let credentials = Credentials::new("Mufasa", "Circle of Life");
+        let mut challenge = Challenge {
+            realm: "http-auth@example.org".to_string(),
+            nonce: "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v".to_string(),
+            opaque: Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".to_string()),
+            algorithm: Algorithm::Md5,
+            session: false,
+            qop: vec!["auth".to_string(), "auth-int".to_string()],
+            userhash: false,
+            stale: false,
+        };
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        let answer_challenge = |challenge: &Challenge| {
+            answer(
+                &credentials,
+                challenge,
+                "GET",
+                "/dir/index.html",
+                None,
+                1,
+                cnonce,
+            )
+        };
+
+        assert_eq!(
+            answer_challenge(&challenge).unwrap(),
+            "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+             uri=\"/dir/index.html\", algorithm=MD5, \
+             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", nc=00000001, \
+             cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", qop=auth, \
+             response=\"8ca523f5e9506fed4657c9700eebdbec\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""
+        );
+        challenge.algorithm = Algorithm::Sha256;
+        assert!(answer_challenge(&challenge).unwrap().contains(
+            "response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1\""
+        ));
+
+        // Example of RFC 2617, Section 3.5
+        let credentials = Credentials::new("Mufasa", "Circle Of Life");
+        let challenge = Challenge {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            opaque: None,
+            algorithm: Algorithm::Md5,
+            session: false,
+            qop: vec!["auth".to_string()],
+            userhash: false,
+            stale: false,
+        };
+        let authorization = answer(
+            &credentials,
+            &challenge,
+            "GET",
+            "/dir/index.html",
+            None,
+            1,
+            "0a4f113b",
+        )
+        .unwrap();
+        assert!(authorization.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        let authorization = answer(&credentials, &challenge, "GET", "/", None, 26, "0a4f113b");
+        assert!(authorization.unwrap().contains("nc=0000001a"));
+
+        let challenge = Challenge {
+            qop: vec!["unknown".to_string()],
+            ..challenge
+        };
+        assert_eq!(
+            answer(&credentials, &challenge, "GET", "/", None, 1, "0a4f113b"),
+            None
+        );
+    }
+}
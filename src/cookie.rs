@@ -0,0 +1,543 @@
+use crate::request::Request;
+use crate::url::Parts;
+use crate::HeaderMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Labels under which country code domains commonly let anyone register,
+/// like `co.uk`, `com.au` or `ne.jp`.
+const SECOND_LEVEL_SUFFIXES: [&str; 18] = [
+    "ac", "co", "com", "edu", "gen", "go", "gob", "gov", "gv", "ltd", "mil", "ne", "net", "nic",
+    "or", "org", "plc", "sch",
+];
+
+/// Stores the cookies set by responses and sends them with the requests they
+/// match, following RFC 6265.
+///
+/// Without the Public Suffix List, a Domain attribute naming a public
+/// suffix is only recognized for single labels like `com` and for common
+/// second-level suffixes of country codes like `co.uk` or `com.au`. Such
+/// cookies are rejected, unless the suffix is the host itself.
+///
+/// Attached to sessions with `SessionBuilder::cookie_jar`, several sessions
+/// may share one jar. The cookies can be saved to and loaded from a file in
+/// the Netscape format used by curl and most browser extensions.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// In lowercase, without a leading dot.
+    domain: String,
+    /// Whether the cookie is only sent to `domain` itself, not to its
+    /// subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// `None` for a session cookie, which never expires.
+    expires: Option<SystemTime>,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    /// Reads a cookie file in the Netscape format, see `read`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<CookieJar> {
+        CookieJar::read(File::open(path)?)
+    }
+
+    /// Reads cookies in the Netscape format, one per line with the tab
+    /// separated fields domain, include subdomains, path, secure, expiry
+    /// and name and value.
+    ///
+    /// Comments and malformed lines are skipped, as are expired cookies.
+    pub fn read<R: Read>(reader: R) -> io::Result<CookieJar> {
+        let now = SystemTime::now();
+        let mut cookies = Vec::new();
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if let Some(cookie) = parse_netscape_line(line.trim_end_matches('\r')) {
+                if !cookie.is_expired(now) {
+                    cookies.push(cookie);
+                }
+            }
+        }
+        Ok(CookieJar {
+            cookies: Mutex::new(cookies),
+        })
+    }
+
+    /// Saves all cookies to `path` in the Netscape format, replacing the
+    /// file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Writes all cookies in the Netscape format. Session cookies are
+    /// written with an expiry of 0, which is read back as a session cookie.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let now = SystemTime::now();
+        writeln!(writer, "# Netscape HTTP Cookie File")?;
+        for cookie in self.cookies.lock().unwrap().iter() {
+            if cookie.is_expired(now) {
+                continue;
+            }
+            let expires = cookie
+                .expires
+                .map_or(0, |expires| unix_seconds(expires).max(1));
+            writeln!(
+                writer,
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                if cookie.host_only { "FALSE" } else { "TRUE" },
+                cookie.path,
+                if cookie.secure { "TRUE" } else { "FALSE" },
+                expires,
+                cookie.name,
+                cookie.value
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Stores the cookie of the Set-Cookie field value `set_cookie` as if it
+    /// was received from `url`. Invalid cookies are ignored.
+    pub fn add_cookie(&self, set_cookie: &str, url: &str) {
+        let now = SystemTime::now();
+        if let Some(cookie) = parse_set_cookie(set_cookie, url, now) {
+            self.insert(cookie, now);
+        }
+    }
+
+    /// Removes all cookies.
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+
+    /// Stores the cookies set by `headers` of a response from `url`.
+    pub(crate) fn store(&self, url: &str, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        for set_cookie in headers.get_all("Set-Cookie") {
+            let set_cookie = match set_cookie.to_str() {
+                Some(set_cookie) => set_cookie,
+                None => continue,
+            };
+            if let Some(cookie) = parse_set_cookie(set_cookie, url, now) {
+                self.insert(cookie, now);
+            }
+        }
+    }
+
+    /// Adds the cookies matching `request` as a Cookie field, unless it
+    /// sets one itself.
+    pub(crate) fn apply(&self, request: &mut Request) {
+        if request.headers.contains_key("Cookie") {
+            return;
+        }
+        if let Some(cookies) = self.cookie_header(&request.url) {
+            request.headers.append("Cookie", cookies);
+        }
+    }
+
+    /// The value of the Cookie field for a request to `url`, `None` if no
+    /// cookie matches.
+    fn cookie_header(&self, url: &str) -> Option<String> {
+        let parts = Parts::parse(url);
+        let (scheme, host, _) = parts.origin()?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let path = if parts.path.is_empty() {
+            "/"
+        } else {
+            parts.path
+        };
+        let now = SystemTime::now();
+
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|cookie| {
+                let domain_matches = if cookie.host_only {
+                    host == cookie.domain
+                } else {
+                    domain_matches(host, &cookie.domain)
+                };
+                domain_matches
+                    && path_matches(path, &cookie.path)
+                    && (!cookie.secure || scheme == "https")
+            })
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        // Longer paths first, otherwise the older cookie first
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let pairs: Vec<String> = matching
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+
+    /// Replaces the cookie with the same name, domain and path, an expired
+    /// `cookie` only removes it.
+    fn insert(&self, cookie: Cookie, now: SystemTime) {
+        let mut cookies = self.cookies.lock().unwrap();
+        let existing = cookies.iter().position(|existing| {
+            existing.name == cookie.name
+                && existing.domain == cookie.domain
+                && existing.path == cookie.path
+        });
+        match existing {
+            _ if cookie.is_expired(now) => {
+                if let Some(pos) = existing {
+                    cookies.remove(pos);
+                }
+            }
+            // Keeps the position, which orders cookies by creation
+            Some(pos) => cookies[pos] = cookie,
+            None => cookies.push(cookie),
+        }
+    }
+}
+
+impl Cookie {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Parses a Set-Cookie field value as described in RFC 6265, Section 5.2
+/// and 5.3, `None` if the cookie is to be ignored.
+fn parse_set_cookie(set_cookie: &str, url: &str, now: SystemTime) -> Option<Cookie> {
+    let parts = Parts::parse(url);
+    let (scheme, host, _) = parts.origin()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let mut attributes = set_cookie.split(';');
+    let (name, value) = attributes.next()?.split_once('=')?;
+    let (name, value) = (name.trim(), value.trim());
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut expires = None;
+    let mut max_age = None;
+    let mut domain = None;
+    let mut path = None;
+    let mut secure = false;
+    let mut http_only = false;
+    for attribute in attributes {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "expires" => expires = parse_date(value).or(expires),
+            "max-age" => {
+                if let Ok(seconds) = value.parse::<i64>() {
+                    // Zero or negative expires the cookie right away
+                    max_age = if seconds > 0 {
+                        now.checked_add(Duration::from_secs(seconds as u64))
+                    } else {
+                        Some(UNIX_EPOCH)
+                    };
+                }
+            }
+            "domain" if !value.is_empty() => {
+                domain = Some(value.trim_start_matches('.').to_ascii_lowercase())
+            }
+            "path" if value.starts_with('/') => path = Some(value.to_string()),
+            "secure" => secure = true,
+            "httponly" => http_only = true,
+            _ => {}
+        }
+    }
+
+    // Only a secure origin may set secure cookies
+    if secure && scheme != "https" {
+        return None;
+    }
+    let (domain, host_only) = match domain {
+        Some(domain) if is_public_suffix(&domain) && domain != host => return None,
+        Some(domain) if is_public_suffix(&domain) => (domain, true),
+        Some(domain) if !domain_matches(host, &domain) => return None,
+        Some(domain) => (domain, false),
+        None => (host.to_string(), true),
+    };
+    Some(Cookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain,
+        host_only,
+        path: path.unwrap_or_else(|| default_path(parts.path)),
+        secure,
+        http_only,
+        expires: max_age.or(expires),
+    })
+}
+
+fn parse_netscape_line(line: &str) -> Option<Cookie> {
+    let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+        Some(line) => (line, true),
+        None if line.starts_with('#') => return None,
+        None => (line, false),
+    };
+    let mut fields = line.split('\t');
+    let domain = fields.next()?;
+    let include_subdomains = fields.next()?;
+    let path = fields.next()?;
+    let secure = fields.next()?;
+    let expires = fields.next()?.parse::<u64>().ok()?;
+    let name = fields.next()?;
+    let value = fields.next().unwrap_or("");
+    if domain.is_empty() || name.is_empty() {
+        return None;
+    }
+    Some(Cookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain: domain.trim_start_matches('.').to_ascii_lowercase(),
+        host_only: !include_subdomains.eq_ignore_ascii_case("TRUE"),
+        path: path.to_string(),
+        secure: secure.eq_ignore_ascii_case("TRUE"),
+        http_only,
+        expires: match expires {
+            0 => None,
+            seconds => UNIX_EPOCH.checked_add(Duration::from_secs(seconds)),
+        },
+    })
+}
+
+/// Whether `domain` is likely a public suffix, under which anyone can
+/// register a domain. A single label like `com` most likely is, as is a
+/// common second-level label under a country code like `co.uk`.
+fn is_public_suffix(domain: &str) -> bool {
+    let mut labels = domain.rsplit('.');
+    let top = labels.next().unwrap_or("");
+    match (labels.next(), labels.next()) {
+        (None, _) => true,
+        (Some(second), None) => top.len() == 2 && SECOND_LEVEL_SUFFIXES.contains(&second),
+        _ => false,
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains, see RFC 6265,
+/// Section 5.1.3.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.ends_with('.'))
+            && host.parse::<IpAddr>().is_err())
+}
+
+/// See RFC 6265, Section 5.1.4.
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The directory of `path`, see RFC 6265, Section 5.1.4.
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(pos) if pos > 0 && path.starts_with('/') => path[..pos].to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+/// Parses the date of an Expires attribute with the lenient algorithm of
+/// RFC 6265, Section 5.1.1.
+pub(crate) fn parse_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    let tokens = date
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+        .filter(|token| !token.is_empty());
+    for token in tokens {
+        let digits = token.bytes().take_while(u8::is_ascii_digit).count();
+        if time.is_none() && token.contains(':') {
+            let fields: Option<Vec<u64>> = token
+                .split(':')
+                .map(|field| field.parse().ok().filter(|_| field.len() <= 2))
+                .collect();
+            if let Some([hour, minute, second]) = fields.as_deref() {
+                time = Some((*hour, *minute, *second));
+                continue;
+            }
+        }
+        if day.is_none() && (1..=2).contains(&digits) {
+            day = token[..digits].parse::<u64>().ok();
+        } else if month.is_none() && token.len() >= 3 && digits == 0 {
+            let prefix = token[..3].to_ascii_lowercase();
+            month = MONTHS
+                .iter()
+                .position(|month| *month == prefix)
+                .map(|index| index as u64 + 1);
+        } else if year.is_none() && (2..=4).contains(&digits) {
+            year = token[..digits].parse::<i64>().ok();
+        }
+    }
+
+    let (hour, minute, second) = time?;
+    let (day, month, mut year) = (day?, month?, year?);
+    if (0..70).contains(&year) {
+        year += 2000;
+    } else if (70..100).contains(&year) {
+        year += 1900;
+    }
+    if !(1..=31).contains(&day) || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+    // Dates before 1970 are in the past anyway
+    Some(UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64))
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784111777);
+        for date in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_date(date), Some(expected), "{}", date);
+        }
+        assert_eq!(
+            parse_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(UNIX_EPOCH)
+        );
+        assert_eq!(parse_date("Sun, 06 Nov 1994"), None);
+        assert_eq!(parse_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn matching() {
+        let jar = CookieJar::new();
+        jar.add_cookie("a=1", "http://example.com/dir/page");
+        jar.add_cookie(
+            "b=2; Domain=.Example.com; Path=/",
+            "http://www.example.com/",
+        );
+        jar.add_cookie("c=3; Secure", "https://example.com/dir/");
+        jar.add_cookie("d=4; Domain=com", "http://example.com/");
+        jar.add_cookie("e=5; Domain=other.com", "http://example.com/");
+        jar.add_cookie("g=7; Domain=co.uk", "http://evil.co.uk/");
+        jar.add_cookie("h=8; Domain=example.co.uk", "http://www.example.co.uk/");
+        jar.add_cookie("f=6; Secure", "http://example.com/");
+
+        assert_eq!(
+            jar.cookie_header("https://example.com/dir/x").as_deref(),
+            Some("a=1; c=3; b=2")
+        );
+        assert_eq!(
+            jar.cookie_header("http://example.com/dir").as_deref(),
+            Some("a=1; b=2")
+        );
+        assert_eq!(
+            jar.cookie_header("http://sub.example.com/dir/").as_deref(),
+            Some("b=2")
+        );
+        assert_eq!(
+            jar.cookie_header("http://example.com/directory"),
+            Some("b=2".to_string())
+        );
+        assert_eq!(jar.cookie_header("http://notexample.com/"), None);
+        assert_eq!(jar.cookie_header("http://victim.co.uk/"), None);
+        assert_eq!(
+            jar.cookie_header("http://example.co.uk/").as_deref(),
+            Some("h=8")
+        );
+
+        // Replaced, then removed
+        jar.add_cookie("a=7; Path=/dir", "http://example.com/");
+        assert_eq!(
+            jar.cookie_header("http://example.com/dir/").as_deref(),
+            Some("a=7; b=2")
+        );
+        jar.add_cookie("a=; Path=/dir; Max-Age=0", "http://example.com/");
+        assert_eq!(
+            jar.cookie_header("http://example.com/dir/").as_deref(),
+            Some("b=2")
+        );
+    }
+
+    #[test]
+    fn netscape_format() {
+        let jar = CookieJar::new();
+        jar.add_cookie("a=1; HttpOnly", "http://example.com/dir/page");
+        jar.add_cookie(
+            "b=2; Domain=example.com; Path=/; Secure; Expires=Fri, 01 Jan 2100 00:00:00 GMT",
+            "https://example.com/",
+        );
+        jar.add_cookie("c=3; Max-Age=0", "http://example.com/");
+        let mut file = Vec::new();
+        jar.write(&mut file).unwrap();
+        assert_eq!(
+            String::from_utf8(file.clone()).unwrap(),
+            "# Netscape HTTP Cookie File\n\
+             #HttpOnly_example.com\tFALSE\t/dir\tFALSE\t0\ta\t1\n\
+             .example.com\tTRUE\t/\tTRUE\t4102444800\tb\t2\n"
+        );
+
+        let loaded = CookieJar::read(&file[..]).unwrap();
+        assert_eq!(
+            *loaded.cookies.lock().unwrap(),
+            *jar.cookies.lock().unwrap()
+        );
+
+        let jar = CookieJar::read(
+            &b"# comment\r\n\
+               example.org\tFALSE\t/\tFALSE\t1\texpired\tx\r\n\
+               malformed line\r\n\
+               example.org\tFALSE\t/\tFALSE\t0\tsession\t\r\n"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            jar.cookie_header("http://example.org/").as_deref(),
+            Some("session=")
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::request::{Body, Request};
+use crate::{Error, ErrorKind, Response, ResponseBody, Session, StatusCode};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Downloads the body of `request` to `path` as described for
+/// `RequestBuilder::download_to`.
+///
+/// The body is written to `path` with the suffix `.part` first, and the
+/// validator the response was requested with, its ETag or Last-Modified
+/// field, to the suffix `.part.validator`. A partial body with a validator
+/// is resumed by asking for the rest of it with Range and If-Range.
+pub(crate) fn download(
+    session: &Session,
+    mut request: Request,
+    path: &Path,
+) -> Result<Response, Error> {
+    // Ranges refer to the encoded body, which is therefore stored as it is
+    request.decompress = false;
+    // A range the request asks for itself cannot be resumed
+    let resumable = !request.headers.contains_key("Range");
+    let partial = with_suffix(path, ".part");
+    let validator_path = with_suffix(path, ".part.validator");
+    let head = request.clone_head();
+    let body = request.body.as_ref().map(Body::try_clone);
+    let mut request = Some(request);
+    // How often the body was requested
+    let mut attempts = 0;
+    loop {
+        let mut next = request.take().unwrap_or_else(|| {
+            let mut next = head.clone_head();
+            next.body = body.as_ref().and_then(|body| body.as_ref()?.try_clone());
+            next
+        });
+        let validator = fs::read_to_string(&validator_path)
+            .ok()
+            .filter(|_| resumable);
+        let offset = match &validator {
+            Some(_) => fs::metadata(&partial).map_or(0, |metadata| metadata.len()),
+            None => 0,
+        };
+        if let (Some(validator), true) = (validator, offset > 0) {
+            next.headers.insert("Range", format!("bytes={}-", offset));
+            next.headers.insert("If-Range", validator);
+        }
+
+        attempts += 1;
+        let mut response = session.execute(next, true)?;
+        let mut file = match response.status_code() {
+            StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                if content_range_start(&response) != Some(offset) {
+                    // Not the range asked for, the download starts over
+                    remove(&[&partial, &validator_path]);
+                    continue;
+                }
+                OpenOptions::new()
+                    .append(true)
+                    .open(&partial)
+                    .map_err(write_error)?
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                if content_range_len(&response) != Some(offset) {
+                    // Longer than the body on the server, the download starts over
+                    remove(&[&partial, &validator_path]);
+                    continue;
+                }
+                // The partial body is already complete
+                fs::rename(&partial, path).map_err(write_error)?;
+                remove(&[&validator_path]);
+                release(&mut response);
+                return Ok(response);
+            }
+            StatusCode::PARTIAL_CONTENT if resumable => {
+                return Err(Error::new(
+                    ErrorKind::Protocol,
+                    "partial content without a requested range",
+                ))
+            }
+            status if status.is_success() => {
+                let file = File::create(&partial).map_err(write_error)?;
+                match validator_of(&response) {
+                    Some(validator) if resumable => {
+                        fs::write(&validator_path, validator).map_err(write_error)?
+                    }
+                    _ => remove(&[&validator_path]),
+                }
+                file
+            }
+            _ => return Err(Error::from_status(response)),
+        };
+
+        match copy(&mut response, &mut file) {
+            Ok(()) => {
+                drop(file);
+                fs::rename(&partial, path).map_err(write_error)?;
+                remove(&[&validator_path]);
+                release(&mut response);
+                return Ok(response);
+            }
+            Err(err) => {
+                let retry = session
+                    .config
+                    .retry_policy
+                    .delay(&head, &body, Err(&err), attempts);
+                match retry {
+                    Some(delay) => thread::sleep(delay),
+                    None => return Err(err),
+                }
+            }
+        }
+    }
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Removes those of `paths` which exist.
+fn remove(paths: &[&Path]) {
+    for path in paths {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// The validator a later request can resume the body of `response` with,
+/// its ETag unless that is weak, otherwise its Last-Modified field.
+fn validator_of(response: &Response) -> Option<String> {
+    let headers = response.headers();
+    match headers.get("ETag").map(str::trim) {
+        Some(etag) if !etag.starts_with("W/") => Some(etag.to_string()),
+        _ => headers
+            .get("Last-Modified")
+            .map(|date| date.trim().to_string()),
+    }
+}
+
+/// The first byte of a partial response, from a Content-Range field like
+/// `bytes 100-199/200`.
+fn content_range_start(response: &Response) -> Option<u64> {
+    let range = response.headers().get("Content-Range")?.trim();
+    let (unit, range) = range.split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+/// The length of the whole body from the Content-Range field of a 416
+/// response, like `bytes */200`.
+fn content_range_len(response: &Response) -> Option<u64> {
+    let range = response.headers().get("Content-Range")?.trim();
+    let (unit, range) = range.split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    range.trim().strip_prefix("*/")?.trim().parse().ok()
+}
+
+/// Releases the connection of the streaming body of `response`.
+fn release(response: &mut Response) {
+    response.body = ResponseBody::Buffered {
+        data: Vec::new(),
+        pos: 0,
+    };
+}
+
+fn copy(response: &mut Response, file: &mut File) -> Result<(), Error> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = match response.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(read_error(err)),
+        };
+        file.write_all(&buf[..len]).map_err(write_error)?;
+    }
+    file.sync_all().map_err(write_error)
+}
+
+/// The error of the transfer a streaming response failed with.
+fn read_error(err: io::Error) -> Error {
+    if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        return *err.into_inner().unwrap().downcast().unwrap();
+    }
+    Error::new(ErrorKind::Io, err)
+}
+
+fn write_error(err: io::Error) -> Error {
+    Error::new(ErrorKind::BodyWrite, err)
+}
@@ -0,0 +1,176 @@
+use crate::{imp, retry, Response, StatusCode};
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+
+/// The category of an `Error`, independent of the backend which produced it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The URL could not be parsed or uses an unsupported scheme.
+    InvalidUrl,
+    /// The host name (or the proxy's host name) could not be resolved.
+    Dns,
+    /// No connection to the host could be established.
+    Connect,
+    /// The TLS handshake failed or the peer certificate was rejected.
+    Tls,
+    /// A connect, idle or total timeout elapsed.
+    Timeout,
+    /// The maximum number of redirects was exceeded.
+    TooManyRedirects,
+    /// Sending or receiving failed on an established connection.
+    Io,
+    /// The request body could not be read.
+    BodyRead,
+    /// The response body could not be written to its destination.
+    BodyWrite,
+    /// The request body could not be encoded, e.g. as JSON.
+    Encode,
+    /// The response body could not be decoded, e.g. as JSON.
+    Decode,
+    /// The request was cancelled.
+    Aborted,
+    /// The server violated the HTTP protocol.
+    Protocol,
+    /// The server responded with an unsuccessful status code, see
+    /// `Response::error_for_status`.
+    Status,
+    Other,
+}
+
+pub struct Error(Repr);
+
+enum Repr {
+    Backend(imp::Error),
+    Status(Box<Response>),
+    Other(ErrorKind, Box<dyn StdError + Send + Sync>),
+}
+
+unsafe impl Send for Error {}
+
+unsafe impl Sync for Error {}
+
+impl ErrorKind {
+    fn description(self) -> &'static str {
+        match self {
+            ErrorKind::InvalidUrl => "invalid URL",
+            ErrorKind::Dns => "could not resolve host",
+            ErrorKind::Connect => "could not connect to host",
+            ErrorKind::Tls => "TLS error",
+            ErrorKind::Timeout => "operation timed out",
+            ErrorKind::TooManyRedirects => "too many redirects",
+            ErrorKind::Io => "connection error",
+            ErrorKind::BodyRead => "could not read request body",
+            ErrorKind::BodyWrite => "could not write response body",
+            ErrorKind::Encode => "could not encode request body",
+            ErrorKind::Decode => "could not decode response body",
+            ErrorKind::Aborted => "request aborted",
+            ErrorKind::Protocol => "HTTP protocol error",
+            ErrorKind::Status => "unsuccessful HTTP status",
+            ErrorKind::Other => "HTTP request failed",
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str(self.description())
+    }
+}
+
+impl Error {
+    pub(crate) fn new<E>(kind: ErrorKind, source: E) -> Error
+    where
+        E: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        Error(Repr::Other(kind, source.into()))
+    }
+
+    pub(crate) fn from_status(response: Response) -> Error {
+        Error(Repr::Status(Box::new(response)))
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match &self.0 {
+            Repr::Backend(err) => err.kind(),
+            Repr::Status(_) => ErrorKind::Status,
+            Repr::Other(kind, _) => *kind,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        self.kind() == ErrorKind::Timeout
+    }
+
+    /// Whether the error occurred while establishing the connection,
+    /// including name resolution.
+    pub fn is_connect(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Dns | ErrorKind::Connect)
+    }
+
+    /// Whether the error is likely transient, so that sending the same
+    /// request again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Dns | ErrorKind::Connect | ErrorKind::Timeout | ErrorKind::Io => true,
+            ErrorKind::Status => self.status().is_some_and(retry::is_retryable_status),
+            _ => false,
+        }
+    }
+
+    /// The status code of the response, if this error was created by
+    /// `Response::error_for_status`.
+    pub fn status(&self) -> Option<StatusCode> {
+        self.response().map(Response::status_code)
+    }
+
+    pub fn response(&self) -> Option<&Response> {
+        match &self.0 {
+            Repr::Status(response) => Some(response),
+            _ => None,
+        }
+    }
+
+    pub fn into_response(self) -> Option<Response> {
+        match self.0 {
+            Repr::Status(response) => Some(*response),
+            _ => None,
+        }
+    }
+}
+
+impl From<imp::Error> for Error {
+    #[inline]
+    fn from(err: imp::Error) -> Error {
+        Error(Repr::Backend(err))
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match &self.0 {
+            Repr::Backend(err) => write!(f, "{:?}: {:?}", self.kind(), err),
+            Repr::Status(response) => write!(f, "Status({})", response.status_code()),
+            Repr::Other(kind, source) => write!(f, "{:?}: {:?}", kind, source),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match &self.0 {
+            Repr::Status(response) => write!(f, "HTTP status {}", response.status_code()),
+            _ => Display::fmt(&self.kind(), f),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match &self.0 {
+            Repr::Backend(err) => Some(err),
+            Repr::Status(_) => None,
+            Repr::Other(_, source) => Some(&**source),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{Error, RequestHandle, Response};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// The response of an `AsyncRequestBuilder::send_async` call.
+///
+/// The request is already in flight when the future is created, it does not
+/// depend on any particular executor and may be polled from any thread.
+/// Dropping the future before it resolved cancels the request.
+pub struct ResponseFuture {
+    shared: Arc<Mutex<Shared>>,
+    handle: RequestHandle,
+    done: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    result: Option<Result<Response, Error>>,
+    waker: Option<Waker>,
+}
+
+impl ResponseFuture {
+    /// Creates the future, `send` receives the callback completing it.
+    pub(crate) fn new<F>(send: F) -> ResponseFuture
+    where
+        F: FnOnce(Box<dyn FnOnce(Result<Response, Error>) + Send>) -> RequestHandle,
+    {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let shared_ = shared.clone();
+        let handle = send(Box::new(move |result| {
+            let waker = {
+                let mut shared = shared_.lock().unwrap();
+                shared.result = Some(result);
+                shared.waker.take()
+            };
+            // Wake without holding the lock, the task may be polled right away
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }));
+        ResponseFuture {
+            shared,
+            handle,
+            done: false,
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Response, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let result = {
+            let mut shared = self.shared.lock().unwrap();
+            match shared.result.take() {
+                Some(result) => result,
+                None => {
+                    match &shared.waker {
+                        Some(waker) if waker.will_wake(cx.waker()) => {}
+                        _ => shared.waker = Some(cx.waker().clone()),
+                    }
+                    return Poll::Pending;
+                }
+            }
+        };
+        self.done = true;
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if !self.done {
+            self.handle.cancel();
+        }
+    }
+}
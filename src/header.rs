@@ -0,0 +1,265 @@
+use std::fmt::{Debug, Formatter};
+use std::slice;
+
+/// The value of a header field.
+///
+/// Header values are usually ASCII, but servers are free to send arbitrary
+/// bytes, so the raw value is kept and converted on demand.
+#[derive(Clone, PartialEq, Eq, Hash, Default)]
+pub struct HeaderValue(Vec<u8>);
+
+/// An ordered multimap of header fields.
+///
+/// Names are compared case-insensitively, repeated fields such as `Set-Cookie`
+/// are kept in the order they were received.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, HeaderValue)>,
+}
+
+/// Iterator over all `(name, value)` pairs of a `HeaderMap`.
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, (String, HeaderValue)>,
+}
+
+/// Iterator over all values of one header field.
+pub struct GetAll<'a> {
+    inner: slice::Iter<'a, (String, HeaderValue)>,
+    name: &'a str,
+}
+
+impl HeaderValue {
+    pub fn from_bytes(value: &[u8]) -> HeaderValue {
+        HeaderValue(value.to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The value as string, `None` if it is not valid UTF-8.
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl From<&str> for HeaderValue {
+    fn from(value: &str) -> HeaderValue {
+        HeaderValue(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for HeaderValue {
+    fn from(value: String) -> HeaderValue {
+        HeaderValue(value.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for HeaderValue {
+    fn from(value: Vec<u8>) -> HeaderValue {
+        HeaderValue(value)
+    }
+}
+
+impl PartialEq<str> for HeaderValue {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for HeaderValue {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl Debug for HeaderValue {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        Debug::fmt(&String::from_utf8_lossy(&self.0), f)
+    }
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Number of fields, counting every value of a repeated field.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    /// The first value of `name`, `None` if it is absent or not valid UTF-8.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_value(name).and_then(HeaderValue::to_str)
+    }
+
+    /// The raw bytes of the first value of `name`.
+    pub fn get_bytes(&self, name: &str) -> Option<&[u8]> {
+        self.get_value(name).map(HeaderValue::as_bytes)
+    }
+
+    pub fn get_value(&self, name: &str) -> Option<&HeaderValue> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// All values of `name` in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> GetAll<'a> {
+        GetAll {
+            inner: self.entries.iter(),
+            name,
+        }
+    }
+
+    /// The distinct field names, in the order they first appeared.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = Vec::new();
+        for (key, _) in &self.entries {
+            if !keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.entries.iter(),
+        }
+    }
+
+    /// Adds a value, keeping existing values of the same field.
+    pub fn append<V: Into<HeaderValue>>(&mut self, name: &str, value: V) {
+        self.entries.push((name.to_string(), value.into()));
+    }
+
+    /// Sets a value, replacing all existing values of the same field.
+    ///
+    /// The new value takes the position of the first replaced one.
+    pub fn insert<V: Into<HeaderValue>>(&mut self, name: &str, value: V) {
+        match self
+            .entries
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some(pos) => {
+                self.entries[pos] = (name.to_string(), value.into());
+                let mut index = 0;
+                self.entries.retain(|(key, _)| {
+                    let keep = index <= pos || !key.eq_ignore_ascii_case(name);
+                    index += 1;
+                    keep
+                });
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// Removes all values of `name`, returning the first one.
+    pub fn remove(&mut self, name: &str) -> Option<HeaderValue> {
+        let mut removed = None;
+        let mut index = 0;
+        while index < self.entries.len() {
+            if self.entries[index].0.eq_ignore_ascii_case(name) {
+                let (_, value) = self.entries.remove(index);
+                removed.get_or_insert(value);
+            } else {
+                index += 1;
+            }
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a HeaderValue);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a HeaderValue);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, value)| (key.as_str(), value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> Iterator for GetAll<'a> {
+    type Item = &'a HeaderValue;
+
+    fn next(&mut self) -> Option<&'a HeaderValue> {
+        let name = self.name;
+        self.inner
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+impl Debug for HeaderMap {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Parses one raw header line as delivered by the backends, e.g.
+/// `Content-Type: text/plain\r\n`, into `headers`.
+///
+/// Returns `false` if the line is not a header field.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn parse_header_line(line: &[u8], headers: &mut HeaderMap) -> bool {
+    match line.iter().position(|x| *x == b':') {
+        Some(seperator_pos) => {
+            let (key, value) = line.split_at(seperator_pos);
+            let key = String::from_utf8_lossy(key);
+            // Remove ":" and surrounding whitespace including "\r\n"
+            let value = trim(&value[1..]);
+            headers.append(key.trim(), value.to_vec());
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = bytes {
+        if first.is_ascii_whitespace() {
+            bytes = rest;
+        } else {
+            break;
+        }
+    }
+    while let [rest @ .., last] = bytes {
+        if last.is_ascii_whitespace() {
+            bytes = rest;
+        } else {
+            break;
+        }
+    }
+    bytes
+}
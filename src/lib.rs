@@ -11,15 +11,66 @@ extern crate objc_id;
 #[cfg(target_os = "linux")]
 extern crate curl;
 #[cfg(target_os = "linux")]
+extern crate curl_sys;
+#[cfg(target_os = "linux")]
 extern crate libc;
 
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+
 #[cfg(target_os = "windows")]
 extern crate winapi;
-#[cfg(target_os = "windows")]
-#[macro_use]
-extern crate wstr;
 
+use auth::{Credentials, DigestCache};
+use middleware::Chain;
+use proxy::Proxies;
+use request::{Body, Request};
+use session::Config;
 use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use timer::Timer;
+
+mod auth;
+mod cookie;
+mod download;
+mod error;
+mod future;
+pub mod header;
+mod method;
+mod middleware;
+mod multipart;
+mod proxy;
+mod redirect;
+mod request;
+mod retry;
+mod session;
+mod status;
+mod timeouts;
+mod timer;
+mod tls;
+mod url;
+
+pub use cookie::CookieJar;
+pub use error::{Error, ErrorKind};
+pub use future::ResponseFuture;
+pub use header::{HeaderMap, HeaderValue};
+pub use method::{InvalidMethod, Method};
+pub use middleware::{Middleware, RequestParts};
+pub use multipart::{Multipart, Part};
+pub use proxy::{Proxy, ProxyKind};
+pub use redirect::{Redirect, RedirectPolicy};
+pub use retry::RetryPolicy;
+pub use session::SessionBuilder;
+pub use status::{InvalidStatusCode, StatusCode};
+pub use timeouts::Timeouts;
+pub use tls::{Certificate, Identity, TlsConfig, TlsInfo, TlsVersion};
 
 #[cfg(target_os = "linux")]
 #[path = "linux/mod.rs"]
@@ -31,308 +82,3007 @@ mod imp;
 #[path = "windows/mod.rs"]
 mod imp;
 
-pub struct AsyncSession(imp::AsyncSession);
+pub struct AsyncSession {
+    inner: Arc<imp::AsyncSession>,
+    config: Config,
+    /// Sends the requests which are retried after a delay.
+    timer: Arc<Timer>,
+    digest: Arc<DigestCache>,
+}
+
+pub struct Session {
+    inner: imp::Session,
+    config: Config,
+    digest: DigestCache,
+}
+
+pub struct AsyncRequestBuilder<'s> {
+    session: &'s AsyncSession,
+    request: Request<'static>,
+}
 
-pub struct Session(imp::Session);
+pub struct RequestBuilder<'s, 'd> {
+    session: &'s Session,
+    request: Request<'d>,
+}
 
-pub struct AsyncRequestBuilder<'s>(imp::AsyncRequestBuilder<'s>);
+/// Refers to a request sent by `AsyncRequestBuilder::send`.
+pub struct RequestHandle(Arc<Mutex<HandleState>>);
 
-pub struct RequestBuilder<'s, 'd>(imp::RequestBuilder<'s, 'd>);
+/// The exchange currently in flight, which changes with every redirect and
+/// answered Digest challenge.
+#[derive(Default)]
+struct HandleState {
+    hop: usize,
+    current: Option<imp::RequestHandle>,
+    /// The retry waiting for its delay, sent at once if cancelled.
+    retry: Option<(Arc<Timer>, u64)>,
+    cancelled: bool,
+}
 
-pub struct Response(imp::Response);
+pub struct Response {
+    status_code: StatusCode,
+    headers: HeaderMap,
+    body: ResponseBody,
+    url: String,
+    redirects: Vec<String>,
+    attempts: usize,
+    tls_info: Option<TlsInfo>,
+}
 
-pub struct Headers<'a>(imp::Headers<'a>);
+enum ResponseBody {
+    Buffered { data: Vec<u8>, pos: usize },
+    Streaming(Box<dyn Read + Send + Sync>),
+}
 
-pub struct Error(imp::Error);
+#[cfg(feature = "json")]
+fn json_body<T: serde::Serialize + ?Sized>(
+    request: &mut Request,
+    value: &T,
+) -> Result<Vec<u8>, Error> {
+    let body = serde_json::to_vec(value).map_err(|err| Error::new(ErrorKind::Encode, err))?;
+    set_content_type(request, "application/json");
+    Ok(body)
+}
 
-unsafe impl Send for Response {}
+/// Sets the Content-Type of a body, unless the request has one.
+fn set_content_type(request: &mut Request, content_type: &str) {
+    if !request.headers.contains_key("Content-Type") {
+        request.headers.insert("Content-Type", content_type);
+    }
+}
 
-unsafe impl Send for Error {}
+fn new_request<'d>(method: Method, url: &str, config: &Config) -> Result<Request<'d>, Error> {
+    let url = config.resolve_url(url)?;
+    let mut request = Request::new(method, url, config.timeouts, config.proxy.clone());
+    request.decompress = config.decompress;
+    Ok(request)
+}
 
 impl AsyncSession {
     #[inline]
     pub fn new() -> AsyncSession {
-        AsyncSession(imp::AsyncSession::new())
+        SessionBuilder::new().build_async()
     }
 
     #[inline]
-    pub fn request<'s, 'd>(
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::new()
+    }
+
+    /// The timeouts applied to each request unless overridden.
+    #[inline]
+    pub fn timeouts(&self) -> Timeouts {
+        self.config.timeouts
+    }
+
+    #[inline]
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.config.timeouts = timeouts;
+    }
+
+    #[inline]
+    pub fn redirect_policy(&self) -> &RedirectPolicy {
+        &self.config.redirect_policy
+    }
+
+    #[inline]
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.config.redirect_policy = policy;
+    }
+
+    #[inline]
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.config.retry_policy
+    }
+
+    #[inline]
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.config.retry_policy = policy;
+    }
+
+    /// The header fields sent with each request unless it sets them itself.
+    #[inline]
+    pub fn default_headers(&self) -> &HeaderMap {
+        &self.config.headers
+    }
+
+    /// The URL relative URLs of requests are resolved against.
+    #[inline]
+    pub fn base_url(&self) -> Option<&str> {
+        self.config.base_url.as_deref()
+    }
+
+    /// The jar storing the cookies of this session, if it has one.
+    #[inline]
+    pub fn cookie_jar(&self) -> Option<&Arc<CookieJar>> {
+        self.config.cookie_jar.as_ref()
+    }
+
+    #[inline]
+    pub fn request<'s>(
         &'s self,
-        method: &str,
+        method: Method,
         url: &str,
     ) -> Result<AsyncRequestBuilder<'s>, Error> {
-        self.0
-            .request(method, url)
-            .map(AsyncRequestBuilder)
-            .map_err(Error)
+        Ok(AsyncRequestBuilder {
+            session: self,
+            request: new_request(method, url, &self.config)?,
+        })
     }
 }
 
 impl Session {
     #[inline]
     pub fn new() -> Session {
-        Session(imp::Session::new())
+        SessionBuilder::new().build()
     }
 
     #[inline]
-    pub fn request<'s, 'd>(
-        &'s self,
-        method: &str,
-        url: &str,
-    ) -> Result<RequestBuilder<'s, 'd>, Error> {
-        self.0
-            .request(method, url)
-            .map(RequestBuilder)
-            .map_err(Error)
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::new()
     }
-}
 
-impl<'s> AsyncRequestBuilder<'s> {
+    /// The timeouts applied to each request unless overridden.
     #[inline]
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        AsyncRequestBuilder(self.0.header(key, value))
+    pub fn timeouts(&self) -> Timeouts {
+        self.config.timeouts
     }
 
     #[inline]
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        AsyncRequestBuilder(self.0.body_vec(data))
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.config.timeouts = timeouts;
     }
 
     #[inline]
-    pub fn send<T>(mut self, callback: T)
-    where
-        T: Fn(Result<Response, Error>) + Send + 'static,
-    {
-        self.0
-            .send(move |result| callback(result.map(Response).map_err(Error)))
+    pub fn redirect_policy(&self) -> &RedirectPolicy {
+        &self.config.redirect_policy
     }
-}
 
-impl<'s, 'd> RequestBuilder<'s, 'd> {
     #[inline]
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        RequestBuilder(self.0.header(key, value))
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.config.redirect_policy = policy;
     }
 
     #[inline]
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        RequestBuilder(self.0.body_vec(data))
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.config.retry_policy
     }
 
     #[inline]
-    pub fn body_bytes(mut self, data: &'d [u8]) -> Self {
-        RequestBuilder(self.0.body_bytes(data))
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.config.retry_policy = policy;
     }
 
+    /// The header fields sent with each request unless it sets them itself.
     #[inline]
-    pub fn send(mut self) -> Result<Response, Error> {
-        self.0.send().map(Response).map_err(Error)
+    pub fn default_headers(&self) -> &HeaderMap {
+        &self.config.headers
     }
-}
 
-impl<'a> Response {
+    /// The URL relative URLs of requests are resolved against.
     #[inline]
-    pub fn status_code(&self) -> u32 {
-        self.0.status_code()
+    pub fn base_url(&self) -> Option<&str> {
+        self.config.base_url.as_deref()
     }
 
+    /// The jar storing the cookies of this session, if it has one.
     #[inline]
-    pub fn body(&self) -> &[u8] {
-        self.0.body()
+    pub fn cookie_jar(&self) -> Option<&Arc<CookieJar>> {
+        self.config.cookie_jar.as_ref()
     }
 
     #[inline]
-    pub fn headers(&'a self) -> Headers<'a> {
-        Headers(self.0.headers())
+    pub fn request<'s, 'd>(
+        &'s self,
+        method: Method,
+        url: &str,
+    ) -> Result<RequestBuilder<'s, 'd>, Error> {
+        Ok(RequestBuilder {
+            session: self,
+            request: new_request(method, url, &self.config)?,
+        })
     }
-}
 
-impl<'a> Headers<'a> {
-    #[inline]
-    pub fn list(&self) -> Vec<&str> {
-        self.0.list()
+    /// Sends `request`, following redirects as the policy allows.
+    fn execute(&self, mut request: Request, streaming: bool) -> Result<Response, Error> {
+        self.config.apply_defaults(&mut request);
+        let policy = &self.config.redirect_policy;
+        let cookie_jar = self.config.cookie_jar.as_deref();
+        let mut redirects = Vec::new();
+        // The answer to the Digest challenge of the previous exchange
+        let mut authorization: Option<String> = None;
+        // How often the current exchange was sent
+        let mut attempts = 0;
+        loop {
+            // The cookies are added for each hop, the next one may go elsewhere
+            let mut head = request.clone_head();
+            let body = request.body.as_ref().map(Body::try_clone);
+            let answered = authorization.is_some();
+            // Answers the challenge the origin sent last in advance
+            let preempted = if answered {
+                None
+            } else {
+                self.digest.authorization(&head, &body)
+            };
+            if let Some(authorization) = authorization.as_ref().or(preempted.as_ref()) {
+                request
+                    .headers
+                    .insert("Authorization", authorization.as_str());
+            }
+            let preempted = preempted.is_some();
+            if let Some(cookie_jar) = cookie_jar {
+                cookie_jar.apply(&mut request);
+            }
+            let middleware = &self.config.middleware;
+            let (called, answer) = middleware.before(&mut request);
+            let mut sent = request.clone_head();
+            // Redirects, cookies and the response refer to the URL sent to
+            head.url = sent.url.clone();
+            head.method = sent.method.clone();
+            attempts += 1;
+            let result = match answer {
+                Some(response) => Ok(response),
+                None if streaming => self.inner.send_streaming(request).map_err(Error::from),
+                None => self.inner.send(request).map_err(Error::from),
+            };
+            let result = middleware.after(called, &mut sent, result);
+            if let (Some(cookie_jar), Ok(response)) = (cookie_jar, &result) {
+                cookie_jar.store(&head.url, response.headers());
+            }
+            let retry = self
+                .config
+                .retry_policy
+                .delay(&head, &body, result.as_ref(), attempts);
+            if let Some(delay) = retry {
+                thread::sleep(delay);
+                request = head;
+                request.body = body.flatten();
+                continue;
+            }
+            let mut response = result?;
+            response.url = head.url.clone();
+            response.attempts = attempts;
+            attempts = 0;
+
+            authorization = if answered {
+                None
+            } else {
+                self.digest.answer(&head, &body, &response, preempted)
+            };
+            if authorization.is_some() {
+                request = head;
+                request.body = body.flatten();
+                continue;
+            }
+            redirects.push(head.url.clone());
+            match redirect::next_request(policy, head, body, &response, &redirects)? {
+                Some(next) => request = next,
+                None => {
+                    redirects.pop();
+                    response.redirects = redirects;
+                    return Ok(response);
+                }
+            }
+        }
     }
+}
 
+impl Default for AsyncSession {
     #[inline]
-    pub fn get(&self, key: &str) -> Option<&str> {
-        self.0.get(key)
+    fn default() -> AsyncSession {
+        AsyncSession::new()
     }
 }
 
-impl Debug for Error {
+impl Default for Session {
     #[inline]
-    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        self.0.fmt(f)
+    fn default() -> Session {
+        Session::new()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashMap;
-    use std::io::{BufRead, BufReader, Read, Write};
-    use std::net::TcpListener;
-    use std::ops::Deref;
-    use std::sync::mpsc::channel;
-    use std::thread;
-    use std::thread::JoinHandle;
-    use std::time::Duration;
+impl<'s> AsyncRequestBuilder<'s> {
+    #[inline]
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.request.headers.append(key, value);
+        self
+    }
 
-    #[test]
-    fn happy_path_sync() {
-        let mut request_headers = HashMap::new();
-        request_headers.insert("Head", "value");
-        request_headers.insert("Head-Head", "value1");
-        request_headers.insert("Accept", "*/*");
-        request_headers.insert("Accept-Language", "en-en");
-        request_headers.insert("Content-Type", "application/x-www-form-urlencoded");
-        request_headers.insert("User-Agent", "nttp");
+    /// Authenticates the request with the Basic scheme.
+    #[inline]
+    pub fn basic_auth(mut self, username: &str, password: &str) -> Self {
+        let authorization = auth::basic(username, password);
+        self.request.headers.insert("Authorization", authorization);
+        self
+    }
 
-        let mut response_headers = HashMap::new();
-        response_headers.insert("Head-Res", "response");
-        response_headers.insert("Header", "res");
-        response_headers.insert("Content-Length", "3");
+    /// Authenticates the request with `token` as Bearer token.
+    #[inline]
+    pub fn bearer_auth(mut self, token: &str) -> Self {
+        let authorization = format!("Bearer {}", token);
+        self.request.headers.insert("Authorization", authorization);
+        self
+    }
 
-        let join_handle = http_request_verifier(vec![
-            HttpExchange {
-                request_method: "POST",
-                request_body: "ABC".as_bytes(),
-                request_headers: request_headers.clone(),
-                response_status_code: 404,
-                response_reason_phase: "NOT FOUND",
-                response_body: "XYZ".as_bytes(),
-                response_headers: response_headers.clone(),
-            },
-            HttpExchange {
-                request_method: "POST",
-                request_body: "1234".as_bytes(),
-                request_headers,
-                response_status_code: 200,
-                response_reason_phase: "OK",
-                response_body: "890".as_bytes(),
-                response_headers,
-            },
-        ]);
+    /// Answers a Digest challenge of the server with `username` and
+    /// `password` instead of the credentials of the session, see
+    /// `SessionBuilder::digest_auth`.
+    #[inline]
+    pub fn digest_auth(mut self, username: &str, password: &str) -> Self {
+        self.request.digest_auth = Some(Credentials::new(username, password));
+        self
+    }
 
-        let body = "ABC".as_bytes();
+    /// Overrides the total timeout of the session for this request.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeouts.request = Some(timeout);
+        self
+    }
 
-        let session = Session::new();
+    /// Overrides the connect timeout of the session for this request.
+    #[inline]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeouts.connect = Some(timeout);
+        self
+    }
 
-        let response = session
-            .request("POST", "http://localhost:45362/test")
-            .unwrap()
-            .header("Head", "value")
-            .header("Head-Head", "value1")
-            .header("User-Agent", "nttp")
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .header("Accept-Language", "en-en")
-            .header("Accept", "*/*")
-            .body_bytes(body)
-            .send()
-            .unwrap();
+    /// Overrides the idle timeout of the session for this request.
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeouts.idle = Some(timeout);
+        self
+    }
 
-        assert_eq!(response.status_code(), 404);
-        assert_eq!(response.body(), "XYZ".as_bytes());
-        assert_eq!(response.headers().list().len(), 3);
-        assert_eq!(response.headers().get("Head-Res").unwrap(), "response");
-        assert_eq!(response.headers().get("Header").unwrap(), "res");
-        assert_eq!(response.headers().get("Content-Length").unwrap(), "3");
+    /// Sends the request through `proxy` instead of the proxy of the
+    /// session.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.request.proxy = Proxies::All(proxy);
+        self
+    }
 
-        let body = b"1234".to_vec();
+    /// Sends the request without a proxy.
+    #[inline]
+    pub fn direct(mut self) -> Self {
+        self.request.proxy = Proxies::None;
+        self
+    }
 
-        let response = session
-            .request("POST", "http://localhost:45362/test")
-            .unwrap()
-            .body_vec(body)
-            .header("Head", "value")
-            .header("Head-Head", "value1")
-            .header("User-Agent", "nttp")
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .header("Accept-Language", "en-en")
-            .header("Accept", "*/*")
-            .send()
-            .unwrap();
+    #[inline]
+    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
+        self.request.body = Some(Body::Shared(Arc::new(data)));
+        self
+    }
 
-        join_handle.join().unwrap();
+    /// Streams the body from `reader`. With a known `len` it is sent with a
+    /// Content-Length, otherwise using chunked transfer encoding.
+    ///
+    /// Such a body cannot be sent again, so redirects which require it are
+    /// not followed.
+    #[inline]
+    pub fn body_reader<R>(mut self, reader: R, len: Option<u64>) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.request.body = Some(Body::Reader(Box::new(reader), len));
+        self
+    }
 
-        assert_eq!(response.status_code(), 200);
-        assert_eq!(response.body(), "890".as_bytes());
-        assert_eq!(response.headers().list().len(), 3);
-        assert_eq!(response.headers().get("Head-Res").unwrap(), "response");
-        assert_eq!(response.headers().get("Header").unwrap(), "res");
-        assert_eq!(response.headers().get("Content-Length").unwrap(), "3");
+    /// Streams the content of the file at `path` as body.
+    pub fn body_file<P: AsRef<Path>>(self, path: P) -> Result<Self, Error> {
+        let (file, len) = open_body_file(path.as_ref())?;
+        Ok(self.body_reader(file, Some(len)))
     }
 
-    #[test]
-    fn happy_path_async() {
-        let session = AsyncSession::new();
-        let (tx, rx) = channel();
+    /// Sends `fields` URL-encoded as `application/x-www-form-urlencoded`,
+    /// unless the request sets another Content-Type.
+    pub fn form<K, V>(mut self, fields: &[(K, V)]) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        set_content_type(&mut self.request, "application/x-www-form-urlencoded");
+        self.body_vec(url::form_urlencode(fields).into_bytes())
+    }
 
-        let tx_ = tx.clone();
-        session
-            .request(
-                "POST",
-                "http://www.httpbin.org/anything?param1=val1&arg2=123",
-            )
-            .unwrap()
-            .header("Head", "Value1")
-            .body_vec("Hello bin!!".as_bytes().to_vec())
-            .send(move |res| {
-                let res = res.unwrap();
-                eprintln!("{}", String::from_utf8_lossy(res.body()));
-                eprintln!("{:?}", res.status_code());
-                eprintln!("{:?}", res.headers().list());
-                tx_.send(()).unwrap();
-            });
-        let tx_ = tx.clone();
-        session
-            .request("POST", "http://www.httpbin.org/post")
-            .unwrap()
-            .send(move |res| {
-                let _res = res.unwrap();
-                tx_.send(()).unwrap();
-            });
-        let tx_ = tx.clone();
-        session
-            .request("DELETE", "http://www.httpbin.org/delete")
-            .unwrap()
-            .send(move |res| {
-                let _res = res.unwrap();
-                tx_.send(()).unwrap();
-            });
+    /// Sends `multipart` as `multipart/form-data` body.
+    pub fn multipart(mut self, multipart: Multipart) -> Self {
+        self.request
+            .headers
+            .insert("Content-Type", multipart.content_type());
+        self.request.body = Some(multipart.into_body());
+        self
+    }
 
-        thread::sleep(Duration::from_millis(100));
-        drop(session);
+    /// Serializes `value` as JSON body, with a Content-Type of
+    /// `application/json` unless the request sets one.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(mut self, value: &T) -> Result<Self, Error> {
+        let body = json_body(&mut self.request, value)?;
+        Ok(self.body_vec(body))
+    }
 
-        for _i in 0..3 {
-            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    pub fn send<T>(mut self, callback: T) -> RequestHandle
+    where
+        T: FnOnce(Result<Response, Error>) + Send + 'static,
+    {
+        let config = &self.session.config;
+        config.apply_defaults(&mut self.request);
+        let handle = RequestHandle(Arc::default());
+        Hop {
+            session: self.session.inner.clone(),
+            policy: config.redirect_policy.clone(),
+            cookie_jar: config.cookie_jar.clone(),
+            retry_policy: config.retry_policy.clone(),
+            timer: self.session.timer.clone(),
+            digest: self.session.digest.clone(),
+            middleware: config.middleware.clone(),
+            handle: handle.0.clone(),
+            exchanges: 0,
+            attempts: 0,
+            authorization: None,
+            redirects: Vec::new(),
+            callback: Box::new(callback),
         }
+        .send(self.request);
+        handle
     }
 
-    #[test]
-    #[should_panic]
-    fn error_unsupported_url_sync() {
-        Session::new()
-            .request("GET", "moz://a")
-            .unwrap()
-            .send()
-            .unwrap();
+    /// Sends the request and passes `context` back to `handler` together
+    /// with the result, so one handler can serve many requests.
+    #[inline]
+    pub fn send_with_context<C, H>(self, context: C, handler: H) -> RequestHandle
+    where
+        C: Send + 'static,
+        H: FnOnce(C, Result<Response, Error>) + Send + 'static,
+    {
+        self.send(move |result| handler(context, result))
     }
 
-    #[test]
-    #[should_panic]
-    fn error_unsupported_url_async() {
-        let session = AsyncSession::new();
-        let (tx, rx) = channel();
-
-        session.request("GET", "moz://a").unwrap().send(move |res| {
-            tx.send(res).unwrap();
-        });
-
-        rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    /// Sends the request, returning a future which resolves once the
+    /// response was received completely.
+    ///
+    /// Dropping the future before it resolved cancels the request.
+    #[inline]
+    pub fn send_async(self) -> ResponseFuture {
+        ResponseFuture::new(|complete| self.send(complete))
     }
+}
 
-    struct HttpExchange {
+/// One exchange of an asynchronous request, which sends the next one from
+/// its callback if the response is a redirect.
+struct Hop {
+    session: Arc<imp::AsyncSession>,
+    policy: RedirectPolicy,
+    cookie_jar: Option<Arc<CookieJar>>,
+    retry_policy: RetryPolicy,
+    timer: Arc<Timer>,
+    digest: Arc<DigestCache>,
+    middleware: Chain,
+    handle: Arc<Mutex<HandleState>>,
+    /// Exchanges sent so far, which includes retries and answers to Digest
+    /// challenges.
+    exchanges: usize,
+    /// How often the current exchange was sent.
+    attempts: usize,
+    /// The answer to the Digest challenge of the previous exchange.
+    authorization: Option<String>,
+    redirects: Vec<String>,
+    callback: Box<dyn FnOnce(Result<Response, Error>) + Send>,
+}
+
+impl Hop {
+    fn send(mut self, mut request: Request<'static>) {
+        if self.handle.lock().unwrap().cancelled {
+            (self.callback)(Err(Error::new(ErrorKind::Aborted, "request cancelled")));
+            return;
+        }
+
+        let hop = self.exchanges;
+        self.exchanges += 1;
+        let session = self.session.clone();
+        let handle = self.handle.clone();
+        let mut head = request.clone_head();
+        let body = request.body.as_ref().map(Body::try_clone);
+        let answered = self.authorization.is_some();
+        // Answers the challenge the origin sent last in advance
+        let preempted = if answered {
+            None
+        } else {
+            self.digest.authorization(&head, &body)
+        };
+        if let Some(authorization) = self.authorization.as_ref().or(preempted.as_ref()) {
+            request
+                .headers
+                .insert("Authorization", authorization.as_str());
+        }
+        let preempted = preempted.is_some();
+        if let Some(cookie_jar) = &self.cookie_jar {
+            cookie_jar.apply(&mut request);
+        }
+        let (called, answer) = self.middleware.before(&mut request);
+        let mut sent = request.clone_head();
+        // Redirects, cookies and the response refer to the URL sent to
+        head.url = sent.url.clone();
+        head.method = sent.method.clone();
+        self.attempts += 1;
+        if let Some(response) = answer {
+            let result = self.middleware.after(called, &mut sent, Ok(response));
+            return self.receive(head, body, answered, preempted, result);
+        }
+        let current = session.send(request, move |result| {
+            let result = self
+                .middleware
+                .after(called, &mut sent, result.map_err(Error::from));
+            self.receive(head, body, answered, preempted, result)
+        });
+
+        // The callback may already have sent the next hop
+        let mut state = handle.lock().unwrap();
+        if state.cancelled {
+            current.cancel();
+        }
+        if state.current.is_none() || hop >= state.hop {
+            state.hop = hop;
+            state.current = Some(current);
+        }
+    }
+
+    /// Handles the outcome of the exchange `head`, by retrying it, answering
+    /// a Digest challenge, following a redirect or completing the request.
+    fn receive(
+        mut self,
+        head: Request<'static>,
+        body: Option<Option<Body<'static>>>,
+        answered: bool,
+        preempted: bool,
+        result: Result<Response, Error>,
+    ) {
+        if let (Some(cookie_jar), Ok(response)) = (&self.cookie_jar, &result) {
+            cookie_jar.store(&head.url, response.headers());
+        }
+        let retry = self
+            .retry_policy
+            .delay(&head, &body, result.as_ref(), self.attempts);
+        if let Some(delay) = retry {
+            let mut next = head;
+            next.body = body.flatten();
+            let handle = self.handle.clone();
+            let timer = self.timer.clone();
+            // Waiting here would block the other transfers of the session
+            let id = timer.schedule(delay, move || self.send(next));
+            let mut state = handle.lock().unwrap();
+            if state.cancelled {
+                timer.expedite(id);
+            } else {
+                state.retry = Some((timer, id));
+            }
+            return;
+        }
+        let mut response = match result {
+            Ok(response) => response,
+            Err(err) => return (self.callback)(Err(err)),
+        };
+        response.url = head.url.clone();
+        response.attempts = self.attempts;
+        self.attempts = 0;
+
+        self.authorization = if answered {
+            None
+        } else {
+            self.digest.answer(&head, &body, &response, preempted)
+        };
+        if self.authorization.is_some() {
+            let mut next = head;
+            next.body = body.flatten();
+            return self.send(next);
+        }
+        self.redirects.push(head.url.clone());
+        match redirect::next_request(&self.policy, head, body, &response, &self.redirects) {
+            Ok(Some(next)) => self.send(next),
+            Ok(None) => {
+                self.redirects.pop();
+                response.redirects = self.redirects;
+                (self.callback)(Ok(response))
+            }
+            Err(err) => (self.callback)(Err(err)),
+        }
+    }
+}
+
+impl<'s, 'd> RequestBuilder<'s, 'd> {
+    #[inline]
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.request.headers.append(key, value);
+        self
+    }
+
+    /// Authenticates the request with the Basic scheme.
+    #[inline]
+    pub fn basic_auth(mut self, username: &str, password: &str) -> Self {
+        let authorization = auth::basic(username, password);
+        self.request.headers.insert("Authorization", authorization);
+        self
+    }
+
+    /// Authenticates the request with `token` as Bearer token.
+    #[inline]
+    pub fn bearer_auth(mut self, token: &str) -> Self {
+        let authorization = format!("Bearer {}", token);
+        self.request.headers.insert("Authorization", authorization);
+        self
+    }
+
+    /// Answers a Digest challenge of the server with `username` and
+    /// `password` instead of the credentials of the session, see
+    /// `SessionBuilder::digest_auth`.
+    #[inline]
+    pub fn digest_auth(mut self, username: &str, password: &str) -> Self {
+        self.request.digest_auth = Some(Credentials::new(username, password));
+        self
+    }
+
+    /// Overrides the total timeout of the session for this request.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeouts.request = Some(timeout);
+        self
+    }
+
+    /// Overrides the connect timeout of the session for this request.
+    #[inline]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeouts.connect = Some(timeout);
+        self
+    }
+
+    /// Overrides the idle timeout of the session for this request.
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeouts.idle = Some(timeout);
+        self
+    }
+
+    /// Sends the request through `proxy` instead of the proxy of the
+    /// session.
+    #[inline]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.request.proxy = Proxies::All(proxy);
+        self
+    }
+
+    /// Sends the request without a proxy.
+    #[inline]
+    pub fn direct(mut self) -> Self {
+        self.request.proxy = Proxies::None;
+        self
+    }
+
+    #[inline]
+    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
+        self.request.body = Some(Body::Shared(Arc::new(data)));
+        self
+    }
+
+    #[inline]
+    pub fn body_bytes(mut self, data: &'d [u8]) -> Self {
+        self.request.body = Some(Body::Borrowed(data));
+        self
+    }
+
+    /// Streams the body from `reader`. With a known `len` it is sent with a
+    /// Content-Length, otherwise using chunked transfer encoding.
+    ///
+    /// Such a body cannot be sent again, so redirects which require it are
+    /// not followed.
+    #[inline]
+    pub fn body_reader<R>(mut self, reader: R, len: Option<u64>) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.request.body = Some(Body::Reader(Box::new(reader), len));
+        self
+    }
+
+    /// Streams the content of the file at `path` as body.
+    pub fn body_file<P: AsRef<Path>>(self, path: P) -> Result<Self, Error> {
+        let (file, len) = open_body_file(path.as_ref())?;
+        Ok(self.body_reader(file, Some(len)))
+    }
+
+    /// Sends `fields` URL-encoded as `application/x-www-form-urlencoded`,
+    /// unless the request sets another Content-Type.
+    pub fn form<K, V>(mut self, fields: &[(K, V)]) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        set_content_type(&mut self.request, "application/x-www-form-urlencoded");
+        self.body_vec(url::form_urlencode(fields).into_bytes())
+    }
+
+    /// Sends `multipart` as `multipart/form-data` body.
+    pub fn multipart(mut self, multipart: Multipart) -> Self {
+        self.request
+            .headers
+            .insert("Content-Type", multipart.content_type());
+        self.request.body = Some(multipart.into_body());
+        self
+    }
+
+    /// Serializes `value` as JSON body, with a Content-Type of
+    /// `application/json` unless the request sets one.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(mut self, value: &T) -> Result<Self, Error> {
+        let body = json_body(&mut self.request, value)?;
+        Ok(self.body_vec(body))
+    }
+
+    #[inline]
+    pub fn send(self) -> Result<Response, Error> {
+        self.session.execute(self.request, false)
+    }
+
+    /// Sends the request and writes the response body to the file at
+    /// `path`, which is replaced once the body was received completely.
+    ///
+    /// The body is streamed to `path` with the suffix `.part` first. If a
+    /// previous download left such a file, and the server sent an ETag or
+    /// Last-Modified field with it, only the rest of the body is requested
+    /// with a Range field. A server which ignores the range or whose file
+    /// changed sends the whole body, which then replaces the partial one.
+    /// A partial body which is already complete is answered with status 416
+    /// and moved to `path` as it is. Failing transfers are resumed according
+    /// to the retry policy of the session, a failed download can also be
+    /// resumed by calling this again.
+    ///
+    /// The body is not decompressed, it is stored as the server sends it.
+    /// Unsuccessful status codes return an error of kind
+    /// `ErrorKind::Status` without touching the file. The returned response
+    /// is the last one received, its body has already been consumed.
+    pub fn download_to<P: AsRef<Path>>(self, path: P) -> Result<Response, Error> {
+        download::download(self.session, self.request, path.as_ref())
+    }
+
+    /// Sends the request and returns once the response headers arrived.
+    ///
+    /// The body is not buffered, it has to be consumed through the `Read`
+    /// implementation of the returned `Response`. Not every backend supports
+    /// this, those fall back to buffering the whole body.
+    #[inline]
+    pub fn send_streaming(self) -> Result<Response, Error> {
+        self.session.execute(self.request, true)
+    }
+}
+
+impl RequestHandle {
+    /// Stops the transfer, the callback receives an error of kind
+    /// `ErrorKind::Aborted`.
+    ///
+    /// Has no effect if the request already completed.
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = true;
+        if let Some(current) = &state.current {
+            current.cancel();
+        }
+        if let Some((timer, id)) = state.retry.take() {
+            timer.expedite(id);
+        }
+    }
+}
+
+fn open_body_file(path: &Path) -> Result<(File, u64), Error> {
+    let file = File::open(path).map_err(|err| Error::new(ErrorKind::BodyRead, err))?;
+    let len = file
+        .metadata()
+        .map_err(|err| Error::new(ErrorKind::BodyRead, err))?
+        .len();
+    Ok((file, len))
+}
+
+impl Response {
+    /// A response with a buffered body, e.g. for `Middleware` answering a
+    /// request itself.
+    pub fn new(status_code: StatusCode, headers: HeaderMap, body: Vec<u8>) -> Response {
+        Response {
+            status_code,
+            headers,
+            body: ResponseBody::Buffered { data: body, pos: 0 },
+            url: String::new(),
+            redirects: Vec::new(),
+            attempts: 1,
+            tls_info: None,
+        }
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn streaming(
+        status_code: StatusCode,
+        headers: HeaderMap,
+        reader: Box<dyn Read + Send + Sync>,
+    ) -> Response {
+        Response {
+            status_code,
+            headers,
+            body: ResponseBody::Streaming(reader),
+            url: String::new(),
+            redirects: Vec::new(),
+            attempts: 1,
+            tls_info: None,
+        }
+    }
+
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    /// Turns a response with a non-2xx status code into an `Error` which
+    /// carries the response.
+    pub fn error_for_status(self) -> Result<Response, Error> {
+        if self.status_code().is_success() {
+            Ok(self)
+        } else {
+            Err(Error::from_status(self))
+        }
+    }
+
+    /// The complete body, empty if the response was created by
+    /// `RequestBuilder::send_streaming`.
+    #[inline]
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            ResponseBody::Buffered { data, .. } => data,
+            ResponseBody::Streaming(_) => &[],
+        }
+    }
+
+    /// Deserializes the body as JSON, reading it to the end if the response
+    /// is streaming.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::de::DeserializeOwned>(self) -> Result<T, Error> {
+        let result = match &self.body {
+            ResponseBody::Buffered { data, .. } => serde_json::from_slice(data),
+            ResponseBody::Streaming(_) => serde_json::from_reader(self),
+        };
+        result.map_err(|err| {
+            let kind = if err.is_io() {
+                ErrorKind::Io
+            } else {
+                ErrorKind::Decode
+            };
+            Error::new(kind, err)
+        })
+    }
+
+    /// The length of the body, after decoding its Content-Encoding.
+    ///
+    /// `None` for a streaming response without a Content-Length field or
+    /// with a Content-Encoding, whose decoded length is only known once it
+    /// was read.
+    pub fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            ResponseBody::Buffered { data, .. } => Some(data.len() as u64),
+            ResponseBody::Streaming(_) => {
+                let encoding = self.headers.get("Content-Encoding").unwrap_or("identity");
+                if !encoding.trim().eq_ignore_ascii_case("identity") {
+                    return None;
+                }
+                self.headers.get("Content-Length")?.trim().parse().ok()
+            }
+        }
+    }
+
+    /// Whether the body has to be consumed through `Read`.
+    #[inline]
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.body, ResponseBody::Streaming(_))
+    }
+
+    #[inline]
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    #[inline]
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// The URL this response was received from, after following redirects.
+    #[inline]
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The URLs which responded with a followed redirect, in order.
+    #[inline]
+    pub fn redirects(&self) -> &[String] {
+        &self.redirects
+    }
+
+    /// How often the request was sent to `url` before this response was
+    /// received, 1 unless the retry policy of the session sent it again.
+    #[inline]
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// The TLS parameters of the connection, if the session collects them
+    /// with `TlsConfig::tls_info` and the response was received over TLS.
+    ///
+    /// Only the curl backend on Linux collects them. curl only reports them
+    /// for the transfer which established a connection, so they are kept
+    /// for the 256 most recently used connections of the session. A
+    /// response received on a reused connection which is no longer among
+    /// them has none.
+    #[inline]
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls_info.as_ref()
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.body {
+            ResponseBody::Buffered { data, pos } => {
+                let mut remaining = &data[*pos..];
+                let read = remaining.read(buf)?;
+                *pos += read;
+                Ok(read)
+            }
+            ResponseBody::Streaming(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Debug for Response {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("Response")
+            .field("status", &self.status_code)
+            .field("url", &self.url)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::error::Error as StdError;
+    use std::future::Future;
+    use std::io;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::ops::Deref;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn happy_path_sync() {
+        let mut request_headers = HashMap::new();
+        request_headers.insert("Head", "value");
+        request_headers.insert("Head-Head", "value1");
+        request_headers.insert("Accept", "*/*");
+        request_headers.insert("Accept-Language", "en-en");
+        request_headers.insert("Content-Type", "application/x-www-form-urlencoded");
+        request_headers.insert("User-Agent", "nttp");
+
+        let mut response_headers = HashMap::new();
+        response_headers.insert("Head-Res", "response");
+        response_headers.insert("Header", "res");
+        response_headers.insert("Content-Length", "3");
+
+        let join_handle = http_request_verifier(vec![
+            HttpExchange {
+                request_method: "POST",
+                request_body: "ABC".as_bytes(),
+                request_headers: request_headers.clone(),
+                response_status_code: 404,
+                response_reason_phase: "NOT FOUND",
+                response_body: "XYZ".as_bytes(),
+                response_headers: response_headers.clone(),
+            },
+            HttpExchange {
+                request_method: "POST",
+                request_body: "1234".as_bytes(),
+                request_headers,
+                response_status_code: 200,
+                response_reason_phase: "OK",
+                response_body: "890".as_bytes(),
+                response_headers,
+            },
+        ]);
+
+        let body = "ABC".as_bytes();
+
+        let session = Session::new();
+
+        let response = session
+            .request(Method::POST, "http://localhost:45362/test")
+            .unwrap()
+            .header("Head", "value")
+            .header("Head-Head", "value1")
+            .header("User-Agent", "nttp")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Accept-Language", "en-en")
+            .header("Accept", "*/*")
+            .body_bytes(body)
+            .send()
+            .unwrap();
+
+        assert_eq!(response.status_code(), 404);
+        assert_eq!(response.body(), "XYZ".as_bytes());
+        assert_eq!(response.headers().len(), 3);
+        assert_eq!(response.headers().get("Head-Res").unwrap(), "response");
+        assert_eq!(response.headers().get("Header").unwrap(), "res");
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "3");
+        assert_eq!(response.headers().get("content-length").unwrap(), "3");
+        let err = response.error_for_status().unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(err.response().unwrap().body(), "XYZ".as_bytes());
+
+        let body = b"1234".to_vec();
+
+        let response = session
+            .request(Method::POST, "http://localhost:45362/test")
+            .unwrap()
+            .body_vec(body)
+            .header("Head", "value")
+            .header("Head-Head", "value1")
+            .header("User-Agent", "nttp")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Accept-Language", "en-en")
+            .header("Accept", "*/*")
+            .send()
+            .unwrap();
+
+        join_handle.join().unwrap();
+
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body(), "890".as_bytes());
+        assert_eq!(response.headers().len(), 3);
+        assert_eq!(response.headers().get("Head-Res").unwrap(), "response");
+        assert_eq!(response.headers().get("Header").unwrap(), "res");
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "3");
+    }
+
+    #[test]
+    fn happy_path_async() {
+        let session = AsyncSession::new();
+        let (tx, rx) = channel();
+
+        let tx_ = tx.clone();
+        session
+            .request(
+                Method::POST,
+                "http://www.httpbin.org/anything?param1=val1&arg2=123",
+            )
+            .unwrap()
+            .header("Head", "Value1")
+            .body_vec("Hello bin!!".as_bytes().to_vec())
+            .send(move |res| {
+                let res = res.unwrap();
+                eprintln!("{}", String::from_utf8_lossy(res.body()));
+                eprintln!("{:?}", res.status_code());
+                eprintln!("{:?}", res.headers());
+                tx_.send(()).unwrap();
+            });
+        let tx_ = tx.clone();
+        session
+            .request(Method::POST, "http://www.httpbin.org/post")
+            .unwrap()
+            .send(move |res| {
+                let _res = res.unwrap();
+                tx_.send(()).unwrap();
+            });
+        let tx_ = tx.clone();
+        session
+            .request(Method::DELETE, "http://www.httpbin.org/delete")
+            .unwrap()
+            .send(move |res| {
+                let _res = res.unwrap();
+                tx_.send(()).unwrap();
+            });
+
+        thread::sleep(Duration::from_millis(100));
+        drop(session);
+
+        for _i in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn error_unsupported_url_sync() {
+        Session::new()
+            .request(Method::GET, "moz://a")
+            .unwrap()
+            .send()
+            .unwrap();
+    }
+
+    #[test]
+    fn error_kinds() {
+        let session = Session::new();
+        let err = session
+            .request(Method::GET, "moz://a")
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidUrl);
+        assert!(!err.is_retryable());
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = session
+            .request(Method::GET, "http://127.0.0.1:1/")
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Connect);
+        assert!(err.is_connect());
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    #[should_panic]
+    fn error_unsupported_url_async() {
+        let session = AsyncSession::new();
+        let (tx, rx) = channel();
+
+        session
+            .request(Method::GET, "moz://a")
+            .unwrap()
+            .send(move |res| {
+                tx.send(res).unwrap();
+            });
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    }
+
+    #[test]
+    fn method_parsing() {
+        assert_eq!("GET".parse::<Method>().unwrap(), Method::GET);
+        assert_eq!("PATCH".parse::<Method>().unwrap(), Method::PATCH);
+        assert_eq!("PROPFIND".parse::<Method>().unwrap(), "PROPFIND");
+        assert!("".parse::<Method>().is_err());
+        assert!("GET /".parse::<Method>().is_err());
+        assert!(Method::PUT.is_idempotent());
+        assert!(!Method::POST.is_idempotent());
+    }
+
+    #[test]
+    fn status_code_classes() {
+        assert!(StatusCode::NO_CONTENT.is_success());
+        assert!(StatusCode::PERMANENT_REDIRECT.is_redirection());
+        assert!(StatusCode::from_u16(451).unwrap().is_client_error());
+        assert!(StatusCode::BAD_GATEWAY.is_server_error());
+        assert!(StatusCode::from_u16(42).is_err());
+        assert_eq!(StatusCode::NOT_FOUND.to_string(), "404 Not Found");
+        assert_eq!(StatusCode::from_u16(599).unwrap().canonical_reason(), None);
+    }
+
+    #[test]
+    fn header_map_multiple_values() {
+        let mut headers = HeaderMap::new();
+        assert!(header::parse_header_line(
+            b"Set-Cookie: a=1\r\n",
+            &mut headers
+        ));
+        assert!(header::parse_header_line(b"Vary:Accept\r\n", &mut headers));
+        assert!(header::parse_header_line(
+            b"set-cookie: b=2\r\n",
+            &mut headers
+        ));
+        assert!(header::parse_header_line(b"X-Raw: \xff\r\n", &mut headers));
+        assert!(!header::parse_header_line(
+            b"HTTP/1.1 200 OK\r\n",
+            &mut headers
+        ));
+
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+        let cookies: Vec<_> = headers.get_all("Set-Cookie").collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        assert_eq!(headers.get("vary"), Some("Accept"));
+        assert_eq!(headers.get("X-Raw"), None);
+        assert_eq!(headers.get_bytes("X-Raw"), Some(&b"\xff"[..]));
+        assert_eq!(headers.keys(), vec!["Set-Cookie", "Vary", "X-Raw"]);
+
+        headers.insert("Set-Cookie", "c=3");
+        assert_eq!(headers.get_all("set-cookie").count(), 1);
+        assert_eq!(
+            headers.iter().next().unwrap(),
+            ("Set-Cookie", &"c=3".into())
+        );
+        assert_eq!(headers.remove("vary").unwrap(), "Accept");
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json() {
+        use serde_json::{json, Value};
+
+        let (url, server) = test_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 21\r\n\r\n{\"id\": 1, \"ok\": true}".to_vec(),
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 7\r\n\r\n{\"id\": ".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n[1, 2, 3]".to_vec(),
+        ]);
+        let session = Session::new();
+        let response = session
+            .request(Method::POST, &url)
+            .unwrap()
+            .json(&json!({"name": "nttp"}))
+            .unwrap()
+            .send()
+            .unwrap();
+        let value: Value = response.json().unwrap();
+        assert_eq!(value, json!({"id": 1, "ok": true}));
+
+        let err = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send()
+            .unwrap()
+            .json::<Value>()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Decode);
+
+        // Streamed over a new connection, the server closed the previous one
+        let numbers: Vec<u32> = session
+            .request(Method::PUT, &url)
+            .unwrap()
+            .header("Content-Type", "application/vnd.test+json")
+            .json(&[1])
+            .unwrap()
+            .send_streaming()
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(numbers, [1, 2, 3]);
+        drop(session);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].body, br#"{"name":"nttp"}"#);
+        assert!(requests[0]
+            .head
+            .to_ascii_lowercase()
+            .contains("content-type: application/json\r\n"));
+        assert!(requests[2]
+            .head
+            .to_ascii_lowercase()
+            .contains("content-type: application/vnd.test+json\r\n"));
+
+        let mut map = HashMap::new();
+        map.insert(vec![1], 1);
+        let err = Session::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .json(&map)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::Encode);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn decompression() {
+        // "hello " 10 times
+        let gzip = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xcb\x48\xcd\xc9\xc9\x57\xc8\x20\
+                     \x8b\x04\x00\xe4\x4d\xd0\x59\x3c\x00\x00\x00";
+        let mut response = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 29\r\n\
+                             Connection: close\r\n\r\n"
+            .to_vec();
+        response.extend_from_slice(gzip);
+        let (url, server) = test_server(vec![response.clone(), response.clone(), response]);
+
+        let session = Session::new();
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.body(), "hello ".repeat(10).as_bytes());
+        assert_eq!(response.content_length(), Some(60));
+        assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get("Content-Length"), Some("29"));
+
+        let mut response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send_streaming()
+            .unwrap();
+        assert_eq!(response.content_length(), None);
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello ".repeat(10));
+
+        let response = Session::builder()
+            .decompress(false)
+            .build()
+            .request(Method::GET, &url)
+            .unwrap()
+            .send()
+            .unwrap();
+        assert_eq!(response.body(), &gzip[..]);
+
+        let requests = server.join().unwrap();
+        let accept_encoding = |request: &RecordedRequest| {
+            request
+                .head
+                .to_ascii_lowercase()
+                .lines()
+                .find_map(|line| line.strip_prefix("accept-encoding: "))
+                .map(str::to_string)
+        };
+        assert!(accept_encoding(&requests[0]).unwrap().contains("gzip"));
+        assert!(accept_encoding(&requests[1]).unwrap().contains("gzip"));
+        assert_eq!(accept_encoding(&requests[2]), None);
+    }
+
+    #[test]
+    fn streaming_response_body() {
+        let body: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut response =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        response.extend_from_slice(&body);
+        let (url, server) = test_server(vec![response]);
+
+        let mut response = Session::new()
+            .request(Method::GET, &url)
+            .unwrap()
+            .send_streaming()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert!(response.is_streaming());
+        assert!(response.body().is_empty());
+
+        let mut received = Vec::new();
+        response.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), body.len());
+        assert!(received == body);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(requests[0].body.is_empty());
+    }
+
+    #[test]
+    fn streaming_request_body() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok.clone(), ok]);
+        let body: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        let session = Session::new();
+
+        let response = session
+            .request(Method::PUT, &url)
+            .unwrap()
+            .body_reader(io::Cursor::new(body.clone()), Some(body.len() as u64))
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+
+        let response = session
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_reader(io::Cursor::new(body.clone()), None)
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        // Closes the idle connection, the test server serves one at a time
+        drop(session);
+
+        let (sender, receiver) = channel();
+        AsyncSession::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_reader(io::Cursor::new(body.clone()), None)
+            .send(move |result| sender.send(result.map(|r| r.status_code())).unwrap());
+        assert_eq!(
+            receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap()
+                .unwrap(),
+            200
+        );
+
+        let requests = server.join().unwrap();
+        let known = requests[0].head.to_ascii_lowercase();
+        assert!(known.contains(&format!("content-length: {}\r\n", body.len())));
+        assert!(!known.contains("transfer-encoding"));
+        assert!(requests[0].body == body);
+        for request in &requests[1..] {
+            assert!(request
+                .head
+                .to_ascii_lowercase()
+                .contains("transfer-encoding: chunked"));
+            assert!(request.body == body);
+        }
+    }
+
+    #[test]
+    fn body_file() {
+        let path = std::env::temp_dir().join(format!("nttp-body-file-{}", std::process::id()));
+        std::fs::write(&path, b"content of the file").unwrap();
+        let (url, server) = test_server(vec![b"HTTP/1.1 204 No Content\r\n\r\n".to_vec()]);
+
+        let response = Session::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_file(&path)
+            .unwrap()
+            .send()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(response.status_code(), 204);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0]
+            .head
+            .to_ascii_lowercase()
+            .contains("content-length: 19\r\n"));
+        assert_eq!(requests[0].body, b"content of the file");
+
+        let err = Session::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_file(path)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::BodyRead);
+    }
+
+    #[test]
+    fn forms() {
+        let path = std::env::temp_dir().join(format!("nttp-multipart-{}.txt", std::process::id()));
+        std::fs::write(&path, b"content of the file").unwrap();
+        let ok = b"HTTP/1.1 204 No Content\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok]);
+
+        let session = Session::new();
+        session
+            .request(Method::POST, &url)
+            .unwrap()
+            .form(&[("name", "Jane Doe"), ("tags", "a&b")])
+            .send()
+            .unwrap();
+        drop(session);
+
+        let multipart = Multipart::new()
+            .text("title", "Report")
+            .file("upload", &path)
+            .unwrap();
+        let content_type = multipart.content_type();
+        let boundary = content_type.split("boundary=").nth(1).unwrap().to_string();
+        let response = block_on(
+            AsyncSession::new()
+                .request(Method::POST, &url)
+                .unwrap()
+                .multipart(multipart)
+                .send_async(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(response.status_code(), 204);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0]
+            .head
+            .to_ascii_lowercase()
+            .contains("content-type: application/x-www-form-urlencoded\r\n"));
+        assert_eq!(requests[0].body, b"name=Jane+Doe&tags=a%26b");
+
+        let head = requests[1].head.to_ascii_lowercase();
+        assert!(head.contains(&format!(
+            "content-type: multipart/form-data; boundary={}\r\n",
+            boundary
+        )));
+        assert!(head.contains(&format!("content-length: {}\r\n", requests[1].body.len())));
+        let expected = format!(
+            "--{0}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\
+             \r\n\
+             Report\r\n\
+             --{0}\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"{1}\"\r\n\
+             Content-Type: application/octet-stream\r\n\
+             \r\n\
+             content of the file\r\n\
+             --{0}--\r\n",
+            boundary,
+            path.file_name().unwrap().to_str().unwrap()
+        );
+        assert_eq!(String::from_utf8_lossy(&requests[1].body), expected);
+    }
+
+    #[test]
+    fn failing_body_reader() {
+        struct FailingReader;
+
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+
+        // Connections are accepted by the kernel, nobody needs to answer
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+
+        let err = Session::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_reader(FailingReader, None)
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BodyRead);
+        assert_eq!(err.source().unwrap().to_string(), "disk on fire");
+    }
+
+    #[test]
+    fn send_async_future() {
+        let (url, server) = test_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHello".to_vec()
+        ]);
+        let session = AsyncSession::new();
+
+        let future = session
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_vec(b"ping".to_vec())
+            .send_async();
+        let response = block_on(future).unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body(), b"Hello");
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].body, b"ping");
+
+        let err = block_on(
+            session
+                .request(Method::GET, "moz://a")
+                .unwrap()
+                .send_async(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidUrl);
+    }
+
+    #[test]
+    fn send_with_context() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok]);
+        let session = AsyncSession::new();
+        let (sender, receiver) = channel();
+
+        // FnOnce, the sender is moved into the callback and consumed there
+        let once_sender = sender.clone();
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send(move |result| {
+                let sender = once_sender;
+                sender.send((0, result.map(|r| r.status_code()))).unwrap();
+            });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap().0, 0);
+
+        type Completion = (u32, Result<StatusCode, Error>);
+        fn handler((id, sender): (u32, Sender<Completion>), result: Result<Response, Error>) {
+            sender.send((id, result.map(|r| r.status_code()))).unwrap();
+        }
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send_with_context((7, sender.clone()), handler);
+        session
+            .request(Method::GET, "moz://a")
+            .unwrap()
+            .send_with_context((8, sender), handler);
+
+        let mut results: Vec<_> = (0..2)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        results.sort_by_key(|(id, _)| *id);
+        assert_eq!(results[0].0, 7);
+        assert_eq!(results[0].1.as_ref().unwrap(), &200);
+        assert_eq!(results[1].0, 8);
+        assert_eq!(
+            results[1].1.as_ref().unwrap_err().kind(),
+            ErrorKind::InvalidUrl
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn cancel_request() {
+        // Accepts connections without ever responding, reports when they close
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let (event_sender, events) = channel();
+        thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().unwrap();
+                event_sender.send("accepted").unwrap();
+                let _ = socket.read_to_end(&mut Vec::new());
+                event_sender.send("closed").unwrap();
+            }
+        });
+        let session = AsyncSession::new();
+
+        let (sender, receiver) = channel();
+        let handle = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send(move |result| sender.send(result.map(|r| r.status_code())).unwrap());
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "accepted"
+        );
+        handle.cancel();
+        let err = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Aborted);
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "closed"
+        );
+        // Cancelling a finished request is a no-op
+        handle.cancel();
+
+        let future = session.request(Method::GET, &url).unwrap().send_async();
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "accepted"
+        );
+        drop(future);
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "closed"
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        // Accepts connections but never responds
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        thread::spawn(move || {
+            let mut sockets = Vec::new();
+            for socket in listener.incoming() {
+                sockets.push(socket);
+            }
+        });
+
+        let mut session = Session::new();
+        session.set_timeouts(Timeouts::new().request(Duration::from_millis(200)));
+        assert_eq!(session.timeouts().request, Some(Duration::from_millis(200)));
+        let err = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert!(err.is_timeout());
+
+        let err = Session::new()
+            .request(Method::GET, &url)
+            .unwrap()
+            .idle_timeout(Duration::from_secs(1))
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+
+        // Not turned off by being shorter than the resolution of the backend
+        let start = Instant::now();
+        let err = Session::new()
+            .request(Method::GET, &url)
+            .unwrap()
+            .idle_timeout(Duration::from_millis(100))
+            .timeout(Duration::from_secs(30))
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let (sender, receiver) = channel();
+        AsyncSession::new()
+            .request(Method::GET, &url)
+            .unwrap()
+            .timeout(Duration::from_millis(200))
+            .send(move |result| sender.send(result.map(|r| r.status_code())).unwrap());
+        let err = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap_err();
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn redirects() {
+        let (other_url, other) = test_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone".to_vec(),
+        ]);
+        let (url, server) = test_server(vec![
+            b"HTTP/1.1 302 Found\r\nLocation: a/b\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            format!(
+                "HTTP/1.1 303 See Other\r\nLocation: {}final\r\nContent-Length: 0\r\n\r\n",
+                other_url.replace("127.0.0.1", "localhost")
+            )
+            .into_bytes(),
+        ]);
+
+        let response = Session::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .header("Authorization", "Bearer secret")
+            .header("Content-Type", "text/plain")
+            .body_vec(b"data".to_vec())
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body(), b"done");
+        assert_eq!(
+            response.url(),
+            format!("{}final", other_url.replace("127.0.0.1", "localhost"))
+        );
+        assert_eq!(response.redirects(), [url.clone(), format!("{}a/b", url)]);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.starts_with("POST / HTTP/1.1\r\n"));
+        assert_eq!(requests[0].body, b"data");
+        // 302 turns POST into GET, the origin is the same
+        let head = requests[1].head.to_ascii_lowercase();
+        assert!(head.starts_with("get /a/b http/1.1\r\n"));
+        assert!(head.contains("authorization: bearer secret\r\n"));
+        assert!(!head.contains("content-type"));
+        assert!(requests[1].body.is_empty());
+
+        let requests = other.join().unwrap();
+        let head = requests[0].head.to_ascii_lowercase();
+        assert!(head.starts_with("get /final http/1.1\r\n"));
+        assert!(!head.contains("authorization"));
+    }
+
+    #[test]
+    fn redirect_keeps_method_and_body() {
+        let (url, server) = test_server(vec![
+            b"HTTP/1.1 307 Temporary Redirect\r\nLocation: /again\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 308 Permanent Redirect\r\nLocation: /again\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+        ]);
+
+        let (sender, receiver) = channel();
+        AsyncSession::new()
+            .request(Method::PUT, &url)
+            .unwrap()
+            .body_vec(b"data".to_vec())
+            .send(move |result| sender.send(result).unwrap());
+        let response = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status_code(), 201);
+        assert_eq!(response.url(), format!("{}again", url));
+        assert_eq!(response.redirects(), [url.as_str()]);
+
+        // A streamed body cannot be sent again
+        let response = Session::new()
+            .request(Method::POST, &url)
+            .unwrap()
+            .body_reader(io::Cursor::new(b"data".to_vec()), None)
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 308);
+        assert!(response.redirects().is_empty());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.starts_with("PUT / HTTP/1.1\r\n"));
+        assert!(requests[1].head.starts_with("PUT /again HTTP/1.1\r\n"));
+        for request in &requests {
+            assert_eq!(request.body, b"data");
+        }
+    }
+
+    #[test]
+    fn redirect_policies() {
+        let redirect = |location: &str| {
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+                location
+            )
+            .into_bytes()
+        };
+
+        let (url, server) = test_server(vec![redirect("/next")]);
+        let mut session = Session::new();
+        session.set_redirect_policy(RedirectPolicy::none());
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 302);
+        assert_eq!(response.url(), url);
+        assert!(response.redirects().is_empty());
+        server.join().unwrap();
+
+        let (url, server) = test_server(vec![redirect("/1"), redirect("/2")]);
+        session.set_redirect_policy(RedirectPolicy::limited(1));
+        let err = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TooManyRedirects);
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        let (url, server) = test_server(vec![redirect("/go"), redirect("/stop")]);
+        session.set_redirect_policy(RedirectPolicy::custom(|redirect| {
+            assert_eq!(redirect.status(), 302);
+            !redirect.url().ends_with("/stop")
+        }));
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 302);
+        assert_eq!(response.url(), format!("{}go", url));
+        assert_eq!(response.redirects(), [url.as_str()]);
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retries() {
+        let unavailable =
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n"
+                .to_vec();
+        let busy = b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let (url, server) = test_server(vec![
+            unavailable.clone(),
+            busy.clone(),
+            ok.clone(),
+            unavailable.clone(),
+            busy.clone(),
+            unavailable.clone(),
+            unavailable,
+            busy,
+            ok,
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 60\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+        ]);
+        let policy =
+            RetryPolicy::new(3).backoff(Duration::from_millis(1), Duration::from_millis(10));
+        let session = Session::builder().retry_policy(policy.clone()).build();
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body(), b"ok");
+        assert_eq!(response.attempts(), 3);
+
+        // Only idempotent requests are retried
+        let response = session.request(Method::POST, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 503);
+        assert_eq!(response.attempts(), 1);
+        // The last response is returned once the attempts are exhausted
+        let response = session.request(Method::PUT, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 503);
+        assert_eq!(response.attempts(), 3);
+        drop(session);
+
+        let mut session = AsyncSession::builder().retry_policy(policy).build_async();
+        let response = block_on(session.request(Method::GET, &url).unwrap().send_async()).unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.attempts(), 2);
+
+        // Cancelling a request waiting for its retry ends it right away
+        session.set_retry_policy(
+            RetryPolicy::new(2).backoff(Duration::from_millis(1), Duration::from_secs(60)),
+        );
+        let (sender, receiver) = channel();
+        let handle = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send(move |result| sender.send(result).unwrap());
+        thread::sleep(Duration::from_millis(200));
+        handle.cancel();
+        let err = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Aborted);
+        drop(session);
+
+        let methods: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.head.split(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            methods,
+            ["GET", "GET", "GET", "POST", "PUT", "PUT", "PUT", "GET", "GET", "GET"]
+        );
+
+        // Connection failures are retried as well
+        let port = TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let err = Session::builder()
+            .retry_policy(RetryPolicy::new(2).backoff(Duration::ZERO, Duration::ZERO))
+            .build()
+            .request(Method::GET, &format!("http://127.0.0.1:{}/", port))
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Connect);
+    }
+
+    #[test]
+    fn middleware() {
+        struct Signer(Arc<Mutex<Vec<String>>>);
+        impl Middleware for Signer {
+            fn request(&self, request: &mut RequestParts) -> Option<Response> {
+                let signature = format!("{} {}", request.method(), request.url());
+                request.headers_mut().insert("X-Signature", signature);
+                self.0.lock().unwrap().push("sign".to_string());
+                None
+            }
+
+            fn response(
+                &self,
+                request: &RequestParts,
+                result: Result<Response, Error>,
+            ) -> Result<Response, Error> {
+                assert!(request.headers().contains_key("X-Signature"));
+                self.0.lock().unwrap().push("verify".to_string());
+                result
+                    .map(|mut response| {
+                        response.headers_mut().insert("X-Verified", "1");
+                        response
+                    })
+                    .and_then(Response::error_for_status)
+            }
+        }
+        struct Cache(Arc<Mutex<Vec<String>>>);
+        impl Middleware for Cache {
+            fn request(&self, request: &mut RequestParts) -> Option<Response> {
+                self.0.lock().unwrap().push("lookup".to_string());
+                if !request.url().ends_with("/cached") {
+                    return None;
+                }
+                Some(Response::new(
+                    StatusCode::OK,
+                    HeaderMap::new(),
+                    b"cached".to_vec(),
+                ))
+            }
+
+            fn response(
+                &self,
+                _: &RequestParts,
+                result: Result<Response, Error>,
+            ) -> Result<Response, Error> {
+                self.0.lock().unwrap().push("store".to_string());
+                result
+            }
+        }
+
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let failed = b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), failed, ok]);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let builder = Session::builder()
+            .middleware(Signer(log.clone()))
+            .middleware(Cache(log.clone()));
+
+        let session = builder.clone().build();
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.body(), b"ok");
+        assert_eq!(response.headers().get("X-Verified"), Some("1"));
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["sign", "lookup", "store", "verify"]
+        );
+
+        // Answered without being sent
+        let response = session
+            .request(Method::GET, &format!("{}cached", url))
+            .unwrap()
+            .send()
+            .unwrap();
+        assert_eq!(response.body(), b"cached");
+        assert_eq!(response.headers().get("X-Verified"), Some("1"));
+
+        let err = session
+            .request(Method::POST, &url)
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::INTERNAL_SERVER_ERROR));
+        drop(session);
+
+        let session = builder.build_async();
+        let response = block_on(session.request(Method::PUT, &url).unwrap().send_async()).unwrap();
+        assert_eq!(response.headers().get("X-Verified"), Some("1"));
+        let response = block_on(
+            session
+                .request(Method::GET, &format!("{}cached", url))
+                .unwrap()
+                .send_async(),
+        )
+        .unwrap();
+        assert_eq!(response.body(), b"cached");
+        drop(session);
+
+        let requests = server.join().unwrap();
+        let signatures: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                request
+                    .head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("X-Signature: "))
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            signatures,
+            [
+                format!("GET {}", url),
+                format!("POST {}", url),
+                format!("PUT {}", url)
+            ]
+        );
+    }
+
+    #[test]
+    fn middleware_rewrite() {
+        struct Rewrite;
+        impl Middleware for Rewrite {
+            fn request(&self, request: &mut RequestParts) -> Option<Response> {
+                if request.url().ends_with("/old") {
+                    request.set_url("new/page");
+                    request.set_method(Method::PUT);
+                }
+                None
+            }
+        }
+
+        let found = b"HTTP/1.1 302 Found\r\nLocation: next\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let (url, server) = test_server(vec![found.clone(), ok.clone(), found, ok]);
+        let builder = Session::builder().middleware(Rewrite);
+        let old = format!("{}old", url);
+
+        // The redirect is resolved against the rewritten URL and keeps the
+        // rewritten method
+        let session = builder.clone().build();
+        let response = session
+            .request(Method::POST, &old)
+            .unwrap()
+            .body_vec(b"data".to_vec())
+            .send()
+            .unwrap();
+        assert_eq!(response.url(), format!("{}new/next", url));
+        assert_eq!(response.redirects(), [format!("{}new/page", url)]);
+        drop(session);
+
+        let session = builder.build_async();
+        let request = session.request(Method::POST, &old).unwrap();
+        let response = block_on(request.body_vec(b"data".to_vec()).send_async()).unwrap();
+        assert_eq!(response.url(), format!("{}new/next", url));
+        assert_eq!(response.redirects(), [format!("{}new/page", url)]);
+        drop(session);
+
+        let requests: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.head.lines().next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                "PUT /new/page HTTP/1.1",
+                "PUT /new/next HTTP/1.1",
+                "PUT /new/page HTTP/1.1",
+                "PUT /new/next HTTP/1.1"
+            ]
+        );
+    }
+
+    /// Streaming is needed to resume a failed transfer, which not every
+    /// backend supports.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn download() {
+        let truncated = b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 10\r\n\
+            Connection: close\r\n\r\n01234"
+            .to_vec();
+        let rest = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\n\
+            Content-Length: 5\r\n\r\n56789"
+            .to_vec();
+        let changed = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfresh".to_vec();
+        let unsatisfiable = b"HTTP/1.1 416 Range Not Satisfiable\r\n\
+            Content-Range: bytes */5\r\nContent-Length: 0\r\n\r\n"
+            .to_vec();
+        let missing = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![
+            truncated,
+            rest,
+            changed.clone(),
+            unsatisfiable.clone(),
+            unsatisfiable,
+            changed,
+            missing,
+        ]);
+        let path = std::env::temp_dir().join(format!("nttp-download-{}", std::process::id()));
+        let partial = path.with_extension("part");
+        let validator = path.with_extension("part.validator");
+        let session = Session::builder()
+            .retry_policy(RetryPolicy::new(2).backoff(Duration::ZERO, Duration::ZERO))
+            .build();
+
+        let response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .download_to(&path)
+            .unwrap();
+        assert_eq!(response.status_code(), 206);
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        assert!(!partial.exists() && !validator.exists());
+
+        // A partial body which changed on the server is replaced
+        std::fs::write(&partial, "old").unwrap();
+        std::fs::write(&validator, "\"v0\"").unwrap();
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .download_to(&path)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"fresh");
+        assert!(!partial.exists() && !validator.exists());
+
+        // A partial body which is already complete is kept
+        std::fs::write(&partial, "01234").unwrap();
+        std::fs::write(&validator, "\"v2\"").unwrap();
+        let response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .download_to(&path)
+            .unwrap();
+        assert_eq!(response.status_code(), 416);
+        assert_eq!(std::fs::read(&path).unwrap(), b"01234");
+        assert!(!partial.exists() && !validator.exists());
+
+        // One longer than the body on the server is downloaded again
+        std::fs::write(&partial, "0123456").unwrap();
+        std::fs::write(&validator, "\"v2\"").unwrap();
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .download_to(&path)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"fresh");
+        assert!(!partial.exists() && !validator.exists());
+
+        let err = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .download_to(&path)
+            .unwrap_err();
+        assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(std::fs::read(&path).unwrap(), b"fresh");
+        std::fs::remove_file(&path).unwrap();
+        drop(session);
+
+        let ranges: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let field = |name: &str| {
+                    request
+                        .head
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(str::to_string)
+                };
+                (field("Range: "), field("If-Range: "))
+            })
+            .collect();
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(
+            ranges,
+            [
+                (None, None),
+                (some("bytes=5-"), some("\"v1\"")),
+                (some("bytes=3-"), some("\"v0\"")),
+                (some("bytes=5-"), some("\"v2\"")),
+                (some("bytes=7-"), some("\"v2\"")),
+                (None, None),
+                (None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn session_builder() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok]);
+        let builder = Session::builder()
+            .user_agent("nttp-test")
+            .default_header("Accept", "text/plain")
+            .default_header("X-Default", "1")
+            .timeout(Duration::from_secs(5))
+            .redirect_policy(RedirectPolicy::none());
+
+        let session = builder.clone().build();
+        assert_eq!(session.timeouts().request, Some(Duration::from_secs(5)));
+        assert_eq!(
+            session.default_headers().get("user-agent"),
+            Some("nttp-test")
+        );
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .header("Accept", "application/json")
+            .send()
+            .unwrap();
+        // Closes the idle connection, the test server serves one at a time
+        drop(session);
+
+        let (sender, receiver) = channel();
+        builder
+            .build_async()
+            .request(Method::GET, &url)
+            .unwrap()
+            .send(move |result| sender.send(result.map(|r| r.status_code())).unwrap());
+        assert_eq!(
+            receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap()
+                .unwrap(),
+            200
+        );
+
+        let requests = server.join().unwrap();
+        let head = requests[0].head.to_ascii_lowercase();
+        assert!(head.contains("user-agent: nttp-test\r\n"));
+        assert!(head.contains("x-default: 1\r\n"));
+        assert!(head.contains("accept: application/json\r\n"));
+        assert!(!head.contains("text/plain"));
+        let head = requests[1].head.to_ascii_lowercase();
+        assert!(head.contains("accept: text/plain\r\n"));
+    }
+
+    #[test]
+    fn connection_reuse() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok.clone(), ok.clone()]);
+        let session = Session::new();
+        for _ in 0..3 {
+            let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+            assert_eq!(response.body(), b"ok");
+        }
+        let requests = server.join().unwrap();
+        assert!(requests.iter().all(|request| request.connection == 0));
+
+        let (url, server) = test_server(vec![ok.clone(), ok]);
+        let session = Session::builder().max_idle_connections_per_host(0).build();
+        for _ in 0..2 {
+            session.request(Method::GET, &url).unwrap().send().unwrap();
+        }
+        let requests = server.join().unwrap();
+        assert_eq!(requests[1].connection, 1);
+    }
+
+    #[test]
+    fn base_url() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok.clone(), ok.clone(), ok]);
+        let session = Session::builder()
+            .base_url(&format!("{}api/v1/", url))
+            .unwrap()
+            .build();
+        assert_eq!(session.base_url(), Some(format!("{}api/v1/", url).as_str()));
+
+        for path in [
+            "users?id=1",
+            "/status",
+            "../v2/./items",
+            &format!("{}abs", url),
+        ] {
+            let response = session.request(Method::GET, path).unwrap().send().unwrap();
+            assert_eq!(response.status_code(), 200);
+        }
+
+        let paths: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.head.lines().next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "GET /api/v1/users?id=1 HTTP/1.1",
+                "GET /status HTTP/1.1",
+                "GET /api/v2/items HTTP/1.1",
+                "GET /abs HTTP/1.1",
+            ]
+        );
+
+        let err = Session::new()
+            .request(Method::GET, "/status")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidUrl);
+        let err = Session::builder().base_url("api/").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidUrl);
+    }
+
+    #[test]
+    fn cookies() {
+        let (url, server) = test_server(vec![
+            b"HTTP/1.1 302 Found\r\nLocation: sub/page\r\nSet-Cookie: session=abc; Path=/\r\n\
+              Set-Cookie: sub=1; Path=/sub\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nSet-Cookie: session=; Max-Age=0; Path=/\r\n\
+              Content-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let jar = Arc::new(CookieJar::new());
+        let session = Session::builder().cookie_jar(jar.clone()).build();
+        assert!(Arc::ptr_eq(session.cookie_jar().unwrap(), &jar));
+
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 200);
+        session
+            .request(Method::GET, &format!("{}sub/other", url))
+            .unwrap()
+            .send()
+            .unwrap();
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .header("Cookie", "own=1")
+            .send()
+            .unwrap();
+        drop(session);
+
+        let cookies: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| {
+                request
+                    .head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|line| line.strip_prefix("cookie: "))
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            cookies,
+            [
+                None,
+                Some("sub=1; session=abc".to_string()),
+                Some("sub=1".to_string()),
+                Some("own=1".to_string()),
+            ]
+        );
+
+        // Shared with an asynchronous session
+        let (url, server) = test_server(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+        ]);
+        let session = AsyncSession::builder().cookie_jar(jar).build_async();
+        block_on(
+            session
+                .request(Method::GET, &format!("{}sub/", url))
+                .unwrap()
+                .send_async(),
+        )
+        .unwrap();
+        let requests = server.join().unwrap();
+        assert!(requests[0]
+            .head
+            .to_ascii_lowercase()
+            .contains("cookie: sub=1\r\n"));
+    }
+
+    #[test]
+    fn authentication() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let challenge = b"HTTP/1.1 401 Unauthorized\r\n\
+            WWW-Authenticate: Digest realm=\"test\", nonce=\"abc\"\r\nContent-Length: 0\r\n\r\n"
+            .to_vec();
+        let (url, server) = test_server(vec![ok.clone(), ok, challenge]);
+        let session = Session::builder().basic_auth("user", "secret").build();
+        session.request(Method::GET, &url).unwrap().send().unwrap();
+        session
+            .request(Method::GET, &url)
+            .unwrap()
+            .bearer_auth("token")
+            .send()
+            .unwrap();
+        // A Digest challenge is not answered if the request authenticates itself
+        let response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .digest_auth("user", "secret")
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 401);
+        drop(session);
+
+        let authorizations: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| {
+                request
+                    .head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Authorization: "))
+                    .map(str::to_string)
+            })
+            .collect();
+        assert_eq!(
+            authorizations,
+            [
+                Some("Basic dXNlcjpzZWNyZXQ=".to_string()),
+                Some("Bearer token".to_string()),
+                Some("Basic dXNlcjpzZWNyZXQ=".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn digest_authentication() {
+        let challenge = b"HTTP/1.1 401 Unauthorized\r\n\
+            WWW-Authenticate: Basic realm=\"test\"\r\n\
+            WWW-Authenticate: Digest realm=\"test\", qop=\"auth\", algorithm=SHA-256, \
+            nonce=\"abc\", opaque=\"xyz\"\r\nContent-Length: 0\r\n\r\n"
+            .to_vec();
+        let stale = b"HTTP/1.1 401 Unauthorized\r\n\
+            WWW-Authenticate: Digest realm=\"test\", qop=\"auth\", nonce=\"def\", stale=true\r\n\
+            Content-Length: 0\r\n\r\n"
+            .to_vec();
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let (url, server) = test_server(vec![
+            challenge.clone(),
+            ok.clone(),
+            ok.clone(),
+            challenge.clone(),
+            stale,
+            ok.clone(),
+        ]);
+        let session = Session::builder().digest_auth("user", "secret").build();
+        let response = session
+            .request(Method::POST, &format!("{}private?id=1", url))
+            .unwrap()
+            .body_vec(b"data".to_vec())
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body(), b"ok");
+        assert!(response.redirects().is_empty());
+        // The cached challenge is answered in advance, even for a streamed body
+        let response = session
+            .request(Method::POST, &format!("{}private", url))
+            .unwrap()
+            .body_reader(&b"stream"[..], Some(6))
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        // Wrong credentials are only tried once
+        let response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .digest_auth("user", "wrong")
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 401);
+        // A stale nonce is replaced by the new one
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.status_code(), 200);
+        drop(session);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 6);
+        assert!(!requests[0].head.contains("Authorization"));
+        assert!(requests[1]
+            .head
+            .starts_with("POST /private?id=1 HTTP/1.1\r\n"));
+        assert_eq!(requests[1].body, b"data");
+        let authorization = requests[1]
+            .head
+            .lines()
+            .find_map(|line| line.strip_prefix("Authorization: "))
+            .unwrap();
+        for field in [
+            "Digest username=\"user\"",
+            "realm=\"test\"",
+            "uri=\"/private?id=1\"",
+            "algorithm=SHA-256",
+            "nonce=\"abc\"",
+            "qop=auth",
+            "opaque=\"xyz\"",
+        ] {
+            assert!(authorization.contains(field), "{}", authorization);
+        }
+        assert!(authorization.contains("nc=00000001"));
+        assert!(requests[2].head.contains("uri=\"/private\""));
+        assert!(requests[2].head.contains("nc=00000002"));
+        assert_eq!(requests[2].body, b"stream");
+        assert!(requests[3].head.contains("nc=00000003"));
+        assert!(requests[4].head.contains("nc=00000004"));
+        assert!(requests[5].head.contains("nonce=\"def\""));
+        assert!(requests[5].head.contains("nc=00000001"));
+
+        // The asynchronous session answers challenges as well
+        let (url, server) = test_server(vec![challenge, ok.clone(), ok]);
+        let session = AsyncSession::builder()
+            .digest_auth("user", "secret")
+            .build_async();
+        let response = block_on(session.request(Method::GET, &url).unwrap().send_async()).unwrap();
+        assert_eq!(response.status_code(), 200);
+        let response = block_on(session.request(Method::GET, &url).unwrap().send_async()).unwrap();
+        assert_eq!(response.status_code(), 200);
+        drop(session);
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].head.contains("Authorization: Digest"));
+        assert!(requests[2].head.contains("nc=00000002"));
+    }
+
+    #[test]
+    fn proxy() {
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let (proxy_url, proxy) = test_server(vec![ok.clone(), ok.clone()]);
+        let (url, server) = test_server(vec![ok]);
+
+        let session = Session::builder()
+            .proxy(
+                Proxy::new(&proxy_url)
+                    .unwrap()
+                    .basic_auth("user", "pw")
+                    .no_proxy("127.0.0.1"),
+            )
+            .build();
+        let response = session
+            .request(Method::GET, "http://nttp.invalid/path")
+            .unwrap()
+            .send()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        session.request(Method::GET, &url).unwrap().send().unwrap();
+        // Closes the idle connection, the test server serves one at a time
+        drop(session);
+
+        Session::new()
+            .request(Method::GET, "http://nttp.invalid/other")
+            .unwrap()
+            .proxy(Proxy::new(&proxy_url).unwrap())
+            .send()
+            .unwrap();
+
+        let requests = proxy.join().unwrap();
+        let head = requests[0].head.to_ascii_lowercase();
+        assert!(head.starts_with("get http://nttp.invalid/path http/1.1\r\n"));
+        assert!(head.contains("proxy-authorization: basic dxnlcjpwdw==\r\n"));
+        let head = requests[1].head.to_ascii_lowercase();
+        assert!(head.starts_with("get http://nttp.invalid/other http/1.1\r\n"));
+        assert!(!head.contains("proxy-authorization"));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].head.starts_with("GET / HTTP/1.1\r\n"));
+    }
+
+    // The test server needs openssl, which is only a dependency on Linux
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tls() {
+        use openssl::ssl::{SslVerifyMode, SslVersion};
+
+        let ca = Certificate::from_pem(include_bytes!("testdata/ca.pem")).unwrap();
+        let identity = Identity::from_pem(
+            include_bytes!("testdata/client.pem"),
+            include_bytes!("testdata/client.key"),
+        )
+        .unwrap();
+        assert!(Certificate::from_pem(b"not a certificate").is_err());
+        assert!(Identity::from_pem(include_bytes!("testdata/client.pem"), b"").is_err());
+
+        let send = |tls: TlsConfig, url: &str| {
+            let session = Session::builder().tls(tls).build();
+            session.request(Method::GET, url).unwrap().send()
+        };
+
+        let (url, server) = tls_test_server(|_| {}, 3);
+        let err = send(TlsConfig::new(), &url).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Tls);
+        let response = send(TlsConfig::new().add_root_certificate(ca.clone()), &url).unwrap();
+        assert_eq!(response.body(), b"ok");
+        let response = send(TlsConfig::new().danger_accept_invalid_certs(true), &url).unwrap();
+        assert_eq!(response.body(), b"ok");
+        assert_eq!(server.join().unwrap(), [0, 1, 1]);
+
+        // Capped at TLS 1.2, where a missing client certificate fails the
+        // handshake itself
+        let (url, server) = tls_test_server(
+            |acceptor| {
+                acceptor
+                    .set_max_proto_version(Some(SslVersion::TLS1_2))
+                    .unwrap();
+                acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+            },
+            3,
+        );
+        let tls = TlsConfig::new().add_root_certificate(ca);
+        let err = send(tls.clone(), &url).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Tls);
+        let response = send(tls.clone().identity(identity), &url).unwrap();
+        assert_eq!(response.body(), b"ok");
+        let err = send(tls.min_version(TlsVersion::Tls1_3), &url).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Tls);
+        assert_eq!(server.join().unwrap(), [0, 1, 0]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tls_through_proxy() {
+        use std::net::{Shutdown, TcpStream};
+
+        let (url, server) = tls_test_server(|_| {}, 1);
+        // Tunnels a single connection
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let proxy_url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let proxy = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let request = read_request(&mut BufReader::new(client.try_clone().unwrap())).unwrap();
+            let target = request.head.split(' ').nth(1).unwrap();
+            let mut server = TcpStream::connect(target).unwrap();
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\nX-Proxy: 1\r\n\r\n")
+                .unwrap();
+            let (mut client_, mut server_) =
+                (client.try_clone().unwrap(), server.try_clone().unwrap());
+            let upstream = thread::spawn(move || {
+                let _ = io::copy(&mut client_, &mut server_);
+                let _ = server_.shutdown(Shutdown::Write);
+            });
+            let _ = io::copy(&mut server, &mut client);
+            upstream.join().unwrap();
+            request.head
+        });
+
+        let ca = Certificate::from_pem(include_bytes!("testdata/ca.pem")).unwrap();
+        let session = Session::builder()
+            .proxy(Proxy::new(&proxy_url).unwrap())
+            .tls(TlsConfig::new().add_root_certificate(ca))
+            .build();
+        // The status and header fields are those of the server, not those of
+        // the reply to CONNECT
+        let mut response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send_streaming()
+            .unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.headers().get("X-Proxy"), None);
+        assert_eq!(response.headers().get("Content-Length"), Some("2"));
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "ok");
+        drop(response);
+        drop(session);
+
+        assert_eq!(server.join().unwrap(), [1]);
+        assert!(proxy.join().unwrap().starts_with("CONNECT 127.0.0.1:"));
+    }
+
+    /// Runs `tls_system_roots_child` in a process whose system roots are the
+    /// test CA, so that the other tests do not trust it.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tls_system_roots() {
+        let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/ca.pem");
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["tests::tls_system_roots_child", "--exact", "--ignored"])
+            .env("SSL_CERT_FILE", ca)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "run by tls_system_roots"]
+    fn tls_system_roots_child() {
+        // Any other certificate does as additional root
+        let extra = Certificate::from_pem(include_bytes!("testdata/client.pem")).unwrap();
+        let (url, server) = tls_test_server(|_| {}, 1);
+        let session = Session::builder()
+            .tls(TlsConfig::new().add_root_certificate(extra))
+            .build();
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert_eq!(response.body(), b"ok");
+        drop(session);
+        assert_eq!(server.join().unwrap(), [1]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tls_pinning_and_info() {
+        use openssl::sha::sha256;
+        use openssl::x509::X509;
+
+        let certificate = X509::from_pem(include_bytes!("testdata/server.pem")).unwrap();
+        let public_key = certificate
+            .public_key()
+            .unwrap()
+            .public_key_to_der()
+            .unwrap();
+        let ca = Certificate::from_pem(include_bytes!("testdata/ca.pem")).unwrap();
+        let tls = TlsConfig::new().add_root_certificate(ca);
+
+        let (url, server) = tls_test_server(|_| {}, 5);
+        let session = Session::builder()
+            .tls(tls.clone().pin_public_key([0; 32]))
+            .build();
+        let err = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Tls);
+        drop(session);
+
+        // Without collecting
+        let session = Session::builder()
+            .tls(
+                tls.clone()
+                    .pin_public_key([0; 32])
+                    .pin_public_key(sha256(&public_key)),
+            )
+            .build();
+        let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+        assert!(response.tls_info().is_none());
+        drop(session);
+
+        let session = Session::builder().tls(tls.clone().tls_info(true)).build();
+        // Closes its connection once dropped
+        let response = session
+            .request(Method::GET, &url)
+            .unwrap()
+            .send_streaming()
+            .unwrap();
+        assert!(response.tls_info().is_some());
+        drop(response);
+        // The second response is received on the same connection, for which
+        // curl reports no certificates
+        for _ in 0..2 {
+            let response = session.request(Method::GET, &url).unwrap().send().unwrap();
+            let info = response.tls_info().unwrap();
+            assert_eq!(info.peer_certificates(), [certificate.to_der().unwrap()]);
+            assert_eq!(info.version(), Some(TlsVersion::Tls1_3));
+            assert!(info.cipher().unwrap().starts_with("TLS_"));
+        }
+        drop(session);
+
+        let session = AsyncSession::builder()
+            .tls(tls.tls_info(true))
+            .build_async();
+        let future = session.request(Method::GET, &url).unwrap().send_async();
+        let response = block_on(future).unwrap();
+        assert_eq!(
+            response.tls_info().unwrap().version(),
+            Some(TlsVersion::Tls1_3)
+        );
+        drop(session);
+
+        assert_eq!(server.join().unwrap(), [0, 1, 1, 2, 1]);
+    }
+
+    /// Minimal executor which parks the current thread until woken.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(thread::Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    struct RecordedRequest {
+        head: String,
+        body: Vec<u8>,
+        /// Index of the connection the request was received on.
+        connection: usize,
+    }
+
+    /// Serves the raw `responses` in order to whoever connects, one per
+    /// request, and returns the base URL and the recorded requests.
+    fn test_server(responses: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<RecordedRequest>>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            let mut responses = responses.into_iter().peekable();
+            let mut connection = 0;
+            while responses.peek().is_some() {
+                let (mut socket, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(socket.try_clone().unwrap());
+                while let Some(mut request) = read_request(&mut reader) {
+                    request.connection = connection;
+                    requests.push(request);
+                    let response = responses.next().unwrap();
+                    socket.write_all(&response).unwrap();
+                    let close = String::from_utf8_lossy(&response)
+                        .to_ascii_lowercase()
+                        .contains("connection: close");
+                    if close || responses.peek().is_none() {
+                        break;
+                    }
+                }
+                connection += 1;
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    /// Serves `connections` TLS connections with the certificate in
+    /// testdata, responding "ok" to every request until the client closes
+    /// the connection. Returns the number of requests of each connection.
+    #[cfg(target_os = "linux")]
+    fn tls_test_server<F>(configure: F, connections: usize) -> (String, JoinHandle<Vec<usize>>)
+    where
+        F: FnOnce(&mut openssl::ssl::SslAcceptorBuilder),
+    {
+        use openssl::pkey::PKey;
+        use openssl::ssl::{SslAcceptor, SslMethod};
+        use openssl::x509::store::X509StoreBuilder;
+        use openssl::x509::X509;
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        let certificate = X509::from_pem(include_bytes!("testdata/server.pem")).unwrap();
+        let key = PKey::private_key_from_pem(include_bytes!("testdata/server.key")).unwrap();
+        acceptor.set_certificate(&certificate).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        // Client certificates are verified against the same CA
+        let mut store = X509StoreBuilder::new().unwrap();
+        store
+            .add_cert(X509::from_pem(include_bytes!("testdata/ca.pem")).unwrap())
+            .unwrap();
+        acceptor.set_verify_cert_store(store.build()).unwrap();
+        configure(&mut acceptor);
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!(
+            "https://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let handle = thread::spawn(move || {
+            (0..connections)
+                .map(|_| {
+                    let (socket, _) = listener.accept().unwrap();
+                    let stream = match acceptor.accept(socket) {
+                        Ok(stream) => stream,
+                        Err(_) => return 0,
+                    };
+                    let mut reader = BufReader::new(stream);
+                    let mut requests = 0;
+                    while read_request(&mut reader).is_some() {
+                        requests += 1;
+                        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        if reader.get_mut().write_all(response).is_err() {
+                            break;
+                        }
+                    }
+                    requests
+                })
+                .collect()
+        });
+        (url, handle)
+    }
+
+    fn read_request<R: BufRead>(reader: &mut R) -> Option<RecordedRequest> {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return None;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let lower = head.to_ascii_lowercase();
+        let mut body = Vec::new();
+        if lower.contains("transfer-encoding: chunked") {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size).unwrap();
+                let size = usize::from_str_radix(size.trim(), 16).unwrap();
+                let mut chunk = vec![0u8; size + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(line) = lower.lines().find(|l| l.starts_with("content-length:")) {
+            let len: usize = line["content-length:".len()..].trim().parse().unwrap();
+            body.resize(len, 0);
+            reader.read_exact(&mut body).unwrap();
+        }
+        Some(RecordedRequest {
+            head,
+            body,
+            connection: 0,
+        })
+    }
+
+    struct HttpExchange {
         request_method: &'static str,
         request_body: &'static [u8],
         request_headers: HashMap<&'static str, &'static str>,
@@ -343,8 +3093,9 @@ mod tests {
     }
 
     fn http_request_verifier(exchanges: Vec<HttpExchange>) -> JoinHandle<()> {
+        // Bind before spawning so the client cannot connect before the socket listens
+        let listener = TcpListener::bind(("127.0.0.1", 45362)).unwrap();
         thread::spawn(move || {
-            let listener = TcpListener::bind(("127.0.0.1", 45362)).unwrap();
             for exchange in exchanges {
                 let (mut socket, _) = listener.accept().unwrap();
 
@@ -368,9 +3119,9 @@ mod tests {
                     let value = value.split_at(2).1;
                     // Remove "\r\n"
                     let value = value.split_at(value.len() - 2).0;
-                    not_found_headers
-                        .remove(key)
-                        .map(|actual_value| assert_eq!(actual_value, value));
+                    if let Some(actual_value) = not_found_headers.remove(key) {
+                        assert_eq!(actual_value, value);
+                    }
                 }
 
                 assert_eq!(not_found_headers.len(), 0);
@@ -391,7 +3142,7 @@ mod tests {
                 });
 
                 write!(socket, "\r\n").unwrap();
-                socket.write(exchange.response_body).unwrap();
+                socket.write_all(exchange.response_body).unwrap();
             }
         })
     }
@@ -1,14 +1,18 @@
-use crate::imp::{parse_header, Error, Response, SendMutRef};
-use curl::easy::{Easy, List};
+use crate::imp::{parse_header, prepare, status_code, Error, SendMutRef, Tls, Transfer};
+use crate::request::Request;
+use crate::session::Config;
+use crate::{HeaderMap, Response};
+use curl::easy::Easy;
 use curl::multi::{EasyHandle, Multi, WaitFd};
-use libc::{c_void, close, fcntl, pipe2, read, write, O_CLOEXEC, O_NONBLOCK};
+use libc::{c_void, close, pipe2, read, write, O_CLOEXEC, O_NONBLOCK};
 use std::collections::HashMap;
-use std::io::{Cursor, Read};
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
-use std::{mem, thread};
 
 pub(crate) struct Sender<T> {
     fd: i32,
@@ -22,17 +26,23 @@ pub(crate) struct Receiver<T> {
 
 unsafe impl<T> Send for Sender<T> where T: Send {}
 
+// Writes of a single pointer to a pipe are atomic
+unsafe impl<T> Sync for Sender<T> where T: Send {}
+
 unsafe impl<T> Send for Receiver<T> where T: Send {}
 
 macro_rules! syscall {
     ($c:expr) => {
         unsafe {
-            if $c == -1 {
+            // Evaluate only once, a second read or write would consume or
+            // duplicate a message
+            let result = $c;
+            if result == -1 {
                 let errno = *libc::__errno_location();
                 //let errno = *libc::__error();
                 panic!("Errno: {} at {}", errno, stringify!($c));
             }
-            $c
+            result
         }
     };
 }
@@ -56,15 +66,19 @@ impl<T> Sender<T> {
     pub(crate) fn send(&self, obj: T) -> Result<(), ()> {
         let ptr = Box::into_raw(Box::new(obj));
 
-        let bytes_written = syscall!(write(
-            self.fd,
-            &ptr as *const *mut T as *const c_void,
-            size_of::<*mut T>()
-        ));
+        let bytes_written = unsafe {
+            write(
+                self.fd,
+                &ptr as *const *mut T as *const c_void,
+                size_of::<*mut T>(),
+            )
+        };
 
         if bytes_written as usize == size_of::<*mut T>() {
             Ok(())
         } else {
+            // The receiver is gone, so the message has to be dropped here
+            drop(unsafe { Box::from_raw(ptr) });
             Err(())
         }
     }
@@ -103,22 +117,24 @@ impl<T> Drop for Receiver<T> {
 }
 
 pub struct AsyncSession {
-    sender: Sender<Message>,
+    sender: Arc<Sender<Message>>,
+    next_id: AtomicUsize,
+    tls: Tls,
 }
 
-pub struct AsyncRequestBuilder<'s> {
-    session: &'s AsyncSession,
-    easy: Easy,
-    headers: List,
+pub struct RequestHandle {
+    sender: Arc<Sender<Message>>,
+    id: usize,
 }
 
-type CallbackFn = Fn(Result<Response, Error>) + Send;
+type CallbackFn = dyn FnOnce(Result<Response, Error>) + Send;
 
 pub struct Exchange {
     handle: Option<EasyHandle>,
     callback: Box<CallbackFn>,
     body: Vec<u8>,
-    response_headers: HashMap<String, String>,
+    transfer: Transfer,
+    response_headers: HeaderMap,
 }
 
 unsafe impl Send for Exchange {}
@@ -126,17 +142,19 @@ unsafe impl Send for Exchange {}
 unsafe impl Sync for Exchange {}
 
 enum Message {
-    Easy(Easy, Box<Exchange>),
+    Easy(usize, Easy, Box<Exchange>),
+    Cancel(usize),
     Quit,
 }
 
 impl AsyncSession {
-    pub fn new() -> AsyncSession {
+    pub fn new(config: &Config) -> AsyncSession {
         let (tx, rx) = create::<Message>();
 
         thread::spawn(move || {
             let mut multi = Multi::new();
             multi.pipelining(true, true).unwrap();
+            let mut exchanges: HashMap<usize, Box<Exchange>> = HashMap::new();
             let mut quit = false;
             loop {
                 let mut fd = WaitFd::new();
@@ -148,15 +166,14 @@ impl AsyncSession {
                 if fds[0].received_read() {
                     if let Ok(message) = rx.recv() {
                         match *message {
-                            Message::Easy(mut easy, mut exchange) => {
-                                let headers_ = SendMutRef(&mut exchange.response_headers);
-                                let mut first = true;
+                            Message::Easy(id, mut easy, mut exchange) => {
+                                let headers_ = SendMutRef::new(&mut exchange.response_headers);
                                 easy.header_function(move |input| {
-                                    parse_header(input, &mut first, unsafe { headers_.deref() })
+                                    parse_header(input, unsafe { headers_.deref() })
                                 })
                                 .unwrap();
 
-                                let body_ = SendMutRef(&mut exchange.body);
+                                let body_ = SendMutRef::new(&mut exchange.body);
                                 easy.write_function(move |input| {
                                     let body = unsafe { body_.deref() };
                                     body.extend_from_slice(input);
@@ -165,11 +182,16 @@ impl AsyncSession {
                                 .unwrap();
 
                                 let mut handle = multi.add(easy).unwrap();
-                                handle
-                                    .set_token(&*exchange as *const Exchange as usize)
-                                    .unwrap();
+                                handle.set_token(id).unwrap();
                                 exchange.handle = Some(handle);
-                                mem::forget(exchange);
+                                exchanges.insert(id, exchange);
+                            }
+                            Message::Cancel(id) => {
+                                // Already completed exchanges are not found
+                                if let Some(exchange) = exchanges.remove(&id) {
+                                    let _ = multi.remove(exchange.handle.unwrap());
+                                    (exchange.callback)(Err(Error::Cancelled));
+                                }
                             }
                             Message::Quit => {
                                 quit = true;
@@ -181,22 +203,25 @@ impl AsyncSession {
                 let running_handles = multi.perform().unwrap();
                 multi.messages(|message| {
                     if let Some(result) = message.result() {
-                        let exchange = unsafe {
-                            Box::from_raw(message.token().unwrap() as *mut i32 as *mut Exchange)
-                        };
+                        let exchange = exchanges.remove(&message.token().unwrap()).unwrap();
 
-                        let mut easy = multi.remove(exchange.handle.unwrap()).unwrap();
+                        let easy = multi.remove(exchange.handle.unwrap()).unwrap();
 
                         if let Err(err) = result {
-                            (exchange.callback)(Err(Error(err)));
+                            (exchange.callback)(Err(exchange.transfer.convert(err)));
                         } else {
-                            let status_code = easy.response_code().unwrap();
-                            let response = Response {
-                                body: exchange.body,
-                                headers: exchange.response_headers,
-                                status_code,
-                            };
-                            (exchange.callback)(Ok(response));
+                            match status_code(easy.response_code().unwrap()) {
+                                Ok(status_code) => {
+                                    let mut response = Response::new(
+                                        status_code,
+                                        exchange.response_headers,
+                                        exchange.body,
+                                    );
+                                    response.tls_info = exchange.transfer.tls_info(easy.raw());
+                                    (exchange.callback)(Ok(response));
+                                }
+                                Err(err) => (exchange.callback)(Err(err)),
+                            }
                         }
                     }
                 });
@@ -204,73 +229,52 @@ impl AsyncSession {
                     break;
                 }
             }
-            multi.close().unwrap();
         });
-        AsyncSession { sender: tx }
-    }
-
-    pub fn request(&self, method: &str, url: &str) -> Result<AsyncRequestBuilder, Error> {
-        AsyncRequestBuilder::new(self, method, url)
+        AsyncSession {
+            sender: Arc::new(tx),
+            next_id: AtomicUsize::new(0),
+            tls: Tls::new(&config.tls),
+        }
     }
 
-    fn send(&self, easy: Easy, exchange: Exchange) {
+    pub fn send<T>(&self, request: Request<'static>, callback: T) -> RequestHandle
+    where
+        T: FnOnce(Result<Response, Error>) + Send + 'static,
+    {
+        let handle = RequestHandle {
+            sender: self.sender.clone(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        };
+        let (easy, transfer) = match prepare(request, &self.tls) {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                callback(Err(Error::Curl(err)));
+                return handle;
+            }
+        };
+        let exchange = Exchange {
+            handle: None,
+            callback: Box::new(callback),
+            body: Vec::new(),
+            transfer,
+            response_headers: HeaderMap::new(),
+        };
         self.sender
-            .send(Message::Easy(easy, Box::new(exchange)))
+            .send(Message::Easy(handle.id, easy, Box::new(exchange)))
             .unwrap();
+        handle
     }
 }
 
-impl<'s> AsyncRequestBuilder<'s> {
-    fn new(
-        session: &'s AsyncSession,
-        method: &str,
-        url: &str,
-    ) -> Result<AsyncRequestBuilder<'s>, Error> {
-        let mut easy = Easy::new();
-        easy.url(url).map_err(Error)?;
-        easy.custom_request(method).map_err(Error)?;
-
-        Ok(AsyncRequestBuilder {
-            session,
-            easy,
-            headers: List::new(),
-        })
-    }
-
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        let mut data = Cursor::new(data);
-        self.easy
-            .read_function(move |out| Ok(data.read(out).unwrap()))
-            .unwrap();
-        self
-    }
-
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        self.headers
-            .append(format!("{}: {}", key, value).as_str())
-            .unwrap();
-        self
-    }
-
-    pub fn send<T>(mut self, callback: T)
-    where
-        T: Fn(Result<Response, Error>) + Send + 'static,
-    {
-        self.easy.http_headers(self.headers).unwrap();
-        self.session.send(
-            self.easy,
-            Exchange {
-                handle: None,
-                callback: Box::new(callback),
-                body: Vec::new(),
-                response_headers: HashMap::new(),
-            },
-        );
+impl RequestHandle {
+    pub fn cancel(&self) {
+        // Fails only if the session already shut down
+        let _ = self.sender.send(Message::Cancel(self.id));
     }
 }
 
 impl Drop for AsyncSession {
     fn drop(&mut self) {
-        self.sender.send(Message::Quit);
+        let _ = self.sender.send(Message::Quit);
     }
 }
@@ -0,0 +1,208 @@
+use crate::imp::SendMutRef;
+use crate::{TlsInfo, TlsVersion};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use curl::easy::Easy;
+use curl_sys::{
+    curl_certinfo, curl_easy_getinfo, curl_slist, CURL, CURLE_OK, CURLINFO, CURLINFO_CERTINFO,
+    CURLINFO_LOCAL_IP, CURLINFO_LOCAL_PORT, CURLINFO_NUM_CONNECTS, CURLINFO_PRIMARY_IP,
+    CURLINFO_PRIMARY_PORT, CURLINFO_SLIST,
+};
+use foreign_types::ForeignTypeRef;
+use libc::{c_char, c_int, c_long, c_void};
+use openssl::ssl::{SslRef, SslVersion};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+
+// Not exposed by the curl-sys crate
+const CURLINFO_TLS_SSL_PTR: CURLINFO = CURLINFO_SLIST + 45;
+const CURLSSLBACKEND_OPENSSL: c_int = 1;
+
+#[repr(C)]
+struct curl_tlssessioninfo {
+    backend: c_int,
+    internals: *mut c_void,
+}
+
+/// Connections whose TLS information is kept at most, the least recently
+/// used are forgotten first.
+const CAPACITY: usize = 256;
+
+/// Records the protocol version and cipher of a handshake.
+///
+/// They are read from the OpenSSL session of the connection while the
+/// transfer runs, libcurl detaches the connection once it is done.
+#[derive(Default)]
+pub(crate) struct Handshake(Arc<Mutex<Option<Session>>>);
+
+struct Session {
+    version: Option<TlsVersion>,
+    cipher: String,
+}
+
+/// The TLS information of the connections of a session.
+///
+/// curl only reports it for the transfer which established a connection,
+/// so it is looked up here for transfers which reuse one.
+#[derive(Default)]
+pub(crate) struct TlsInfoCache(Mutex<Cache>);
+
+#[derive(Default)]
+struct Cache {
+    clock: u64,
+    connections: HashMap<Connection, (u64, TlsInfo)>,
+}
+
+/// Local and remote address, which identify a TCP connection as long as it
+/// is open.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Connection(String, c_long, String, c_long);
+
+impl Handshake {
+    /// Enables the collection of the TLS information for the next transfer
+    /// of `easy`.
+    pub(crate) fn install(easy: &mut Easy) -> Result<Handshake, curl::Error> {
+        let handshake = Handshake::default();
+        let session = handshake.0.clone();
+        let handle = SendMutRef(easy.raw());
+        easy.certinfo(true)?;
+        easy.progress(true)?;
+        easy.progress_function(move |_, _, _, _| {
+            let mut session = session.lock().unwrap();
+            if session.is_none() {
+                *session = unsafe { openssl_session(handle.0) };
+            }
+            true
+        })?;
+        Ok(handshake)
+    }
+
+    /// The TLS information of the finished or running transfer of
+    /// `handle`.
+    pub(crate) unsafe fn tls_info(
+        &self,
+        handle: *mut CURL,
+        cache: &TlsInfoCache,
+    ) -> Option<TlsInfo> {
+        let connection = Connection(
+            get_string(handle, CURLINFO_LOCAL_IP)?,
+            get_long(handle, CURLINFO_LOCAL_PORT)?,
+            get_string(handle, CURLINFO_PRIMARY_IP)?,
+            get_long(handle, CURLINFO_PRIMARY_PORT)?,
+        );
+        let mut cache = cache.0.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+        if get_long(handle, CURLINFO_NUM_CONNECTS)? == 0 {
+            let (last_used, info) = cache.connections.get_mut(&connection)?;
+            *last_used = clock;
+            return Some(info.clone());
+        }
+
+        let session = self.0.lock().unwrap().take();
+        let peer_certificates = peer_certificates(handle);
+        // A new connection, which may reuse the address of a closed one
+        cache.connections.remove(&connection);
+        if session.is_none() && peer_certificates.is_empty() {
+            return None;
+        }
+        let info = TlsInfo {
+            peer_certificates,
+            version: session.as_ref().and_then(|session| session.version),
+            cipher: session.map(|session| session.cipher),
+        };
+
+        if cache.connections.len() >= CAPACITY {
+            let oldest = cache
+                .connections
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(connection, _)| connection.clone());
+            if let Some(oldest) = oldest {
+                cache.connections.remove(&oldest);
+            }
+        }
+        cache.connections.insert(connection, (clock, info.clone()));
+        Some(info)
+    }
+}
+
+/// The version and cipher of the OpenSSL session of the connection `handle`
+/// uses, once its handshake finished. `None` with another TLS backend.
+unsafe fn openssl_session(handle: *mut CURL) -> Option<Session> {
+    let mut info: *const curl_tlssessioninfo = null_mut();
+    if curl_easy_getinfo(handle, CURLINFO_TLS_SSL_PTR, &mut info) != CURLE_OK || info.is_null() {
+        return None;
+    }
+    let info = &*info;
+    if info.backend != CURLSSLBACKEND_OPENSSL || info.internals.is_null() {
+        return None;
+    }
+    let ssl = SslRef::from_ptr(info.internals.cast());
+    let cipher = ssl.current_cipher()?.name().to_string();
+    let version = ssl.version2().and_then(|version| {
+        [
+            (SslVersion::TLS1, TlsVersion::Tls1_0),
+            (SslVersion::TLS1_1, TlsVersion::Tls1_1),
+            (SslVersion::TLS1_2, TlsVersion::Tls1_2),
+            (SslVersion::TLS1_3, TlsVersion::Tls1_3),
+        ]
+        .iter()
+        .find(|(ssl_version, _)| *ssl_version == version)
+        .map(|(_, version)| *version)
+    });
+    Some(Session { version, cipher })
+}
+
+/// The DER encoded certificates of the "Cert" fields of CURLINFO_CERTINFO,
+/// which are PEM encoded.
+unsafe fn peer_certificates(handle: *mut CURL) -> Vec<Vec<u8>> {
+    let mut certinfo: *mut curl_certinfo = null_mut();
+    if curl_easy_getinfo(handle, CURLINFO_CERTINFO, &mut certinfo) != CURLE_OK || certinfo.is_null()
+    {
+        return Vec::new();
+    }
+    let certinfo = &*certinfo;
+    (0..certinfo.num_of_certs.max(0) as usize)
+        .filter_map(|i| {
+            let mut field: *mut curl_slist = *certinfo.certinfo.add(i);
+            while !field.is_null() {
+                let data = CStr::from_ptr((*field).data).to_string_lossy();
+                if let Some(pem) = data.strip_prefix("Cert:") {
+                    return pem_to_der(pem);
+                }
+                field = (*field).next;
+            }
+            None
+        })
+        .collect()
+}
+
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(str::trim)
+        .collect::<String>();
+    STANDARD.decode(body).ok()
+}
+
+unsafe fn get_long(handle: *mut CURL, info: curl_sys::CURLINFO) -> Option<c_long> {
+    let mut value: c_long = 0;
+    if curl_easy_getinfo(handle, info, &mut value) == CURLE_OK {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+unsafe fn get_string(handle: *mut CURL, info: curl_sys::CURLINFO) -> Option<String> {
+    let mut value: *const c_char = null_mut();
+    if curl_easy_getinfo(handle, info, &mut value) == CURLE_OK && !value.is_null() {
+        Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+    } else {
+        None
+    }
+}
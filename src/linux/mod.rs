@@ -1,12 +1,49 @@
-use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
+use crate::imp::certinfo::{Handshake, TlsInfoCache};
+use crate::request::{Body, Request};
+use crate::url::Parts;
+use crate::{
+    ErrorKind, Identity, Method, Proxy, StatusCode, Timeouts, TlsConfig, TlsInfo, TlsVersion,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use curl::easy::{Easy, List, ReadError, SeekResult, SslVersion};
+use curl::MultiError;
+use std::env;
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{Read, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 mod asynchron;
+mod certinfo;
+mod pool;
+mod stream;
 mod sync;
 
 pub use asynchron::*;
 pub use sync::*;
 
+// Not exposed as is_* method by the curl crate
+const CURLE_WEIRD_SERVER_REPLY: u32 = 8;
+const CURLE_SSL_PINNEDPUBKEYNOTMATCH: u32 = 90;
+// Not exposed by the curl crate
+const CURLOPT_SUPPRESS_CONNECT_HEADERS: curl_sys::CURLoption = curl_sys::CURLOPTTYPE_LONG + 265;
+
+/// Where Linux distributions put the CA bundle of the system, in case
+/// libcurl was built without one.
+const CA_BUNDLES: [&str; 6] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/pki/tls/cacert.pem",
+    "/etc/ssl/cert.pem",
+];
+
 #[derive(Copy, Clone)]
 pub(crate) struct SendMutRef<T>(*mut T);
 
@@ -37,52 +74,443 @@ impl SendSlice {
 
 unsafe impl Send for SendSlice {}
 
-pub struct Response {
-    body: Vec<u8>,
-    status_code: u32,
-    headers: HashMap<String, String>,
+/// Applies the semantics of `method` to `easy` depending on whether and
+/// which body is sent.
+fn configure_method(
+    easy: &mut Easy,
+    method: &Method,
+    body: Option<&Body>,
+) -> Result<(), curl::Error> {
+    // Without a known length curl falls back to chunked transfer encoding
+    match (method.as_str(), body.map(Body::len)) {
+        // A response to HEAD never has a body, even if Content-Length says otherwise
+        ("HEAD", _) => easy.nobody(true),
+        ("GET", None) => easy.get(true),
+        ("PUT", len) => {
+            easy.upload(true)?;
+            match len {
+                Some(Some(len)) => easy.in_filesize(len),
+                Some(None) => Ok(()),
+                None => easy.in_filesize(0),
+            }
+        }
+        ("POST", len) => {
+            easy.post(true)?;
+            match len {
+                Some(Some(len)) => easy.post_field_size(len),
+                Some(None) => Ok(()),
+                None => easy.post_field_size(0),
+            }
+        }
+        (method, Some(len)) => {
+            easy.post(true)?;
+            if let Some(len) = len {
+                easy.post_field_size(len)?;
+            }
+            easy.custom_request(method)
+        }
+        (method, None) => easy.custom_request(method),
+    }
 }
 
-pub struct Headers<'a> {
-    headers: &'a HashMap<String, String>,
+fn configure_timeouts(easy: &mut Easy, timeouts: &Timeouts) -> Result<(), curl::Error> {
+    if let Some(timeout) = timeouts.connect {
+        easy.connect_timeout(timeout)?;
+    }
+    if let Some(timeout) = timeouts.request {
+        easy.timeout(timeout)?;
+    }
+    if let Some(timeout) = timeouts.idle {
+        // Less than one byte per second over the whole period counts as idle.
+        // The period is set in whole seconds, 0 would disable it.
+        let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+        easy.low_speed_limit(1)?;
+        easy.low_speed_time(Duration::from_secs(secs.max(1)))?;
+    }
+    Ok(())
 }
 
-pub struct Error(curl::Error);
+/// Keeps the error of a failed `Read` of the request body, curl itself only
+/// reports that the transfer was aborted.
+#[derive(Clone, Default)]
+pub(crate) struct BodyError(Arc<Mutex<Option<io::Error>>>);
 
-impl Response {
-    pub fn headers(&self) -> Headers {
-        Headers {
-            headers: &self.headers,
+fn configure_proxy(easy: &mut Easy, proxy: Option<&Proxy>) -> Result<(), curl::Error> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        // Otherwise curl would pick up the environment variables itself
+        None => return easy.proxy(""),
+    };
+    // The scheme of the URL selects the kind of proxy
+    easy.proxy(&proxy.url())?;
+    // The reply of the proxy to CONNECT would otherwise reach the header
+    // callback like a response of the server
+    let code = unsafe {
+        curl_sys::curl_easy_setopt(
+            easy.raw(),
+            CURLOPT_SUPPRESS_CONNECT_HEADERS,
+            1 as libc::c_long,
+        )
+    };
+    if code != curl_sys::CURLE_OK {
+        return Err(curl::Error::new(code));
+    }
+    if let Some((username, password)) = proxy.credentials() {
+        easy.proxy_username(username)?;
+        easy.proxy_password(password)?;
+    }
+    Ok(())
+}
+
+/// The TLS settings of a session, prepared once for all of its handles.
+pub(crate) struct Tls {
+    /// The system's root certificates followed by the additional ones, as
+    /// setting a blob replaces the default CA file. An error if the system's
+    /// could not be loaded, which fails every HTTPS request.
+    ca_bundle: Option<Result<Vec<u8>, String>>,
+    identity: Option<Identity>,
+    min_version: Option<TlsVersion>,
+    accept_invalid_certs: bool,
+    /// The pinned keys in the format of CURLOPT_PINNEDPUBLICKEY.
+    pinned_public_keys: Option<String>,
+    info: Option<Arc<TlsInfoCache>>,
+}
+
+impl Tls {
+    pub(crate) fn new(config: &TlsConfig) -> Tls {
+        let ca_bundle = if config.root_certificates.is_empty() {
+            None
+        } else {
+            Some(system_roots().map(|mut bundle| {
+                for certificate in &config.root_certificates {
+                    bundle.push(b'\n');
+                    bundle.extend_from_slice(&certificate.pem);
+                }
+                bundle
+            }))
+        };
+        let pinned_public_keys = if config.pinned_public_keys.is_empty() {
+            None
+        } else {
+            let pins = config
+                .pinned_public_keys
+                .iter()
+                .map(|sha256| format!("sha256//{}", STANDARD.encode(sha256)))
+                .collect::<Vec<_>>();
+            Some(pins.join(";"))
+        };
+        Tls {
+            ca_bundle,
+            identity: config.identity.clone(),
+            min_version: config.min_version,
+            accept_invalid_certs: config.accept_invalid_certs,
+            pinned_public_keys,
+            info: config.collect_info.then(Default::default),
         }
     }
 
-    pub fn status_code(&self) -> u32 {
-        self.status_code
+    fn configure(&self, easy: &mut Easy, url: &str) -> Result<Option<Handshake>, curl::Error> {
+        match &self.ca_bundle {
+            Some(Ok(bundle)) => easy.ssl_cainfo_blob(bundle)?,
+            Some(Err(message)) if is_https(url) => {
+                let mut err = curl::Error::new(curl_sys::CURLE_SSL_CACERT_BADFILE);
+                err.set_extra(message.clone());
+                return Err(err);
+            }
+            _ => {}
+        }
+        if let Some(identity) = &self.identity {
+            easy.ssl_cert_type("PEM")?;
+            easy.ssl_cert_blob(&identity.certificate)?;
+            easy.ssl_key_type("PEM")?;
+            easy.ssl_key_blob(&identity.key)?;
+        }
+        if let Some(version) = self.min_version {
+            let min = match version {
+                TlsVersion::Tls1_0 => SslVersion::Tlsv10,
+                TlsVersion::Tls1_1 => SslVersion::Tlsv11,
+                TlsVersion::Tls1_2 => SslVersion::Tlsv12,
+                TlsVersion::Tls1_3 => SslVersion::Tlsv13,
+            };
+            easy.ssl_min_max_version(min, SslVersion::Default)?;
+        }
+        if self.accept_invalid_certs {
+            easy.ssl_verify_peer(false)?;
+            easy.ssl_verify_host(false)?;
+        }
+        if let Some(pins) = &self.pinned_public_keys {
+            easy.pinned_public_key(pins)?;
+        }
+        match self.info {
+            Some(_) => Handshake::install(easy).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn is_https(url: &str) -> bool {
+    Parts::parse(url)
+        .scheme
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https"))
+}
+
+/// The root certificates libcurl trusts by default, read from the file
+/// named by `SSL_CERT_FILE` as OpenSSL does, the CA bundle libcurl was built
+/// with, or the usual locations of Linux distributions.
+///
+/// Empty if libcurl uses a CA directory instead, which the blob does not
+/// replace.
+fn system_roots() -> Result<Vec<u8>, String> {
+    let version = curl::Version::get();
+    let path = match env::var("SSL_CERT_FILE") {
+        Ok(path) => path,
+        Err(_) => match (version.cainfo(), version.capath()) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(_)) => return Ok(Vec::new()),
+            (None, None) => {
+                return CA_BUNDLES
+                    .iter()
+                    .find_map(|path| fs::read(path).ok())
+                    .ok_or_else(|| "no CA bundle of the system was found".to_string())
+            }
+        },
+    };
+    fs::read(&path).map_err(|err| format!("could not read the CA bundle {}: {}", path, err))
+}
+
+/// What is needed from the setup of a transfer once it is performed.
+pub(crate) struct Transfer {
+    body_error: BodyError,
+    handshake: Option<(Handshake, Arc<TlsInfoCache>)>,
+}
+
+impl Transfer {
+    /// Converts the error of the transfer, preferring the cause of a failed
+    /// body read.
+    pub(crate) fn convert(&self, err: curl::Error) -> Error {
+        self.body_error.convert(err)
     }
 
-    pub fn body(&self) -> &[u8] {
-        &self.body
+    /// The TLS information of the connection `handle` performs the transfer
+    /// on, if the session collects it.
+    pub(crate) fn tls_info(&self, handle: *mut curl_sys::CURL) -> Option<TlsInfo> {
+        let (handshake, cache) = self.handshake.as_ref()?;
+        unsafe { handshake.tls_info(handle, cache) }
     }
 }
 
-impl<'a> Headers<'a> {
-    pub fn list(&self) -> Vec<&str> {
-        self.headers
-            .keys()
-            .into_iter()
-            .map(|x| x.as_str())
-            .collect()
+/// Creates a handle performing `request`. A borrowed body must outlive the
+/// transfer, so the handle must not be performed after `'d` ended.
+pub(crate) fn prepare(request: Request, tls: &Tls) -> Result<(Easy, Transfer), curl::Error> {
+    let mut easy = Easy::new();
+    let transfer = configure(&mut easy, request, tls)?;
+    Ok((easy, transfer))
+}
+
+/// Sets up a new or reset `easy` to perform `request`.
+pub(crate) fn configure(
+    easy: &mut Easy,
+    request: Request,
+    tls: &Tls,
+) -> Result<Transfer, curl::Error> {
+    easy.url(&request.url)?;
+    configure_method(easy, &request.method, request.body.as_ref())?;
+    configure_timeouts(easy, &request.timeouts)?;
+    configure_proxy(easy, request.proxy.for_url(&request.url))?;
+    if request.decompress {
+        // Offers every encoding libcurl was built with
+        easy.accept_encoding("")?;
     }
+    let handshake = tls.configure(easy, &request.url)?;
 
-    pub fn get(&self, key: &str) -> Option<&str> {
-        self.headers.get(key).map(|x| x.as_str())
+    let mut headers = List::new();
+    for (name, value) in &request.headers {
+        headers.append(&format!(
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        ))?;
     }
+    easy.http_headers(headers)?;
+
+    let body_error = match request.body {
+        Some(body) => install_body(easy, body)?,
+        None => BodyError::default(),
+    };
+    Ok(Transfer {
+        body_error,
+        handshake: handshake.zip(tls.info.clone()),
+    })
+}
+
+/// Sets the read function of `easy`.
+fn install_body(easy: &mut Easy, body: Body) -> Result<BodyError, curl::Error> {
+    let body_error = BodyError::default();
+    match body {
+        Body::Borrowed(data) => install_bytes(easy, SendSlice::new(data), None)?,
+        Body::Shared(data) => install_bytes(easy, SendSlice::new(&data), Some(data))?,
+        Body::Reader(mut reader, _) => {
+            let body_error_ = body_error.clone();
+            easy.read_function(move |out| loop {
+                match reader.read(out) {
+                    Ok(read) => return Ok(read),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        *body_error_.0.lock().unwrap() = Some(err);
+                        return Err(ReadError::Abort);
+                    }
+                }
+            })?;
+        }
+    }
+    Ok(body_error)
+}
+
+/// Sets the read and seek functions for a body in memory, so curl can send
+/// it again if a reused connection turns out to be closed. `owner` is kept
+/// alive along with the functions.
+fn install_bytes(
+    easy: &mut Easy,
+    data: SendSlice,
+    owner: Option<Arc<Vec<u8>>>,
+) -> Result<(), curl::Error> {
+    let pos = Arc::new(AtomicUsize::new(0));
+    let pos_ = pos.clone();
+    easy.read_function(move |mut out| {
+        let _owner = &owner;
+        let data = unsafe { data.deref() };
+        let start = pos_.load(Ordering::Relaxed);
+        let written = out.write(&data[start..]).unwrap();
+        pos_.store(start + written, Ordering::Relaxed);
+        Ok(written)
+    })?;
+    easy.seek_function(move |whence| match whence {
+        SeekFrom::Start(offset) if offset <= data.1 as u64 => {
+            pos.store(offset as usize, Ordering::Relaxed);
+            SeekResult::Ok
+        }
+        _ => SeekResult::CantSeek,
+    })
+}
+
+impl BodyError {
+    fn convert(&self, err: curl::Error) -> Error {
+        match self.0.lock().unwrap().take() {
+            Some(io_err) if err.is_aborted_by_callback() => Error::Body(io_err),
+            _ => Error::Curl(err),
+        }
+    }
+}
+
+/// The status code curl reports, which is 0 if no response was received.
+pub(crate) fn status_code(code: u32) -> Result<StatusCode, Error> {
+    StatusCode::from_raw(code).ok_or(Error::InvalidStatus(code))
+}
+
+pub enum Error {
+    Curl(curl::Error),
+    Multi(MultiError),
+    Body(io::Error),
+    /// The status code of the response is not a three-digit number.
+    InvalidStatus(u32),
+    Cancelled,
 }
 
 unsafe impl Send for Error {}
 
+impl Error {
+    pub(crate) fn kind(&self) -> ErrorKind {
+        let err = match self {
+            Error::Curl(err) => err,
+            Error::Multi(_) => return ErrorKind::Other,
+            Error::Body(_) => return ErrorKind::BodyRead,
+            Error::InvalidStatus(_) => return ErrorKind::Protocol,
+            Error::Cancelled => return ErrorKind::Aborted,
+        };
+        if err.is_unsupported_protocol() || err.is_url_malformed() {
+            ErrorKind::InvalidUrl
+        } else if err.is_couldnt_resolve_host() || err.is_couldnt_resolve_proxy() {
+            ErrorKind::Dns
+        } else if err.is_couldnt_connect() {
+            ErrorKind::Connect
+        } else if err.is_ssl_connect_error()
+            || err.code() == CURLE_SSL_PINNEDPUBKEYNOTMATCH
+            || err.is_peer_failed_verification()
+            || err.is_ssl_certproblem()
+            || err.is_ssl_cipher()
+            || err.is_ssl_cacert()
+            || err.is_ssl_cacert_badfile()
+            || err.is_ssl_crl_badfile()
+            || err.is_ssl_issuer_error()
+            || err.is_ssl_shutdown_failed()
+            || err.is_use_ssl_failed()
+        {
+            ErrorKind::Tls
+        } else if err.is_operation_timedout() {
+            ErrorKind::Timeout
+        } else if err.is_too_many_redirects() {
+            ErrorKind::TooManyRedirects
+        } else if err.is_got_nothing()
+            || err.is_send_error()
+            || err.is_recv_error()
+            || err.is_partial_file()
+        {
+            // A partial file means the connection closed before the body ended
+            ErrorKind::Io
+        } else if err.is_read_error() || err.is_send_fail_rewind() {
+            ErrorKind::BodyRead
+        } else if err.is_write_error() {
+            ErrorKind::BodyWrite
+        } else if err.is_aborted_by_callback() {
+            ErrorKind::Aborted
+        } else if err.is_bad_content_encoding() {
+            ErrorKind::Decode
+        } else if err.code() == CURLE_WEIRD_SERVER_REPLY
+            || err.is_http_returned_error()
+            || err.is_http2_error()
+            || err.is_http2_stream_error()
+        {
+            ErrorKind::Protocol
+        } else {
+            ErrorKind::Other
+        }
+    }
+}
+
+impl From<curl::Error> for Error {
+    fn from(err: curl::Error) -> Error {
+        Error::Curl(err)
+    }
+}
+
+impl From<MultiError> for Error {
+    fn from(err: MultiError) -> Error {
+        Error::Multi(err)
+    }
+}
+
 impl Debug for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        self.0.fmt(f)
+        match self {
+            Error::Curl(err) => Debug::fmt(err, f),
+            Error::Multi(err) => Debug::fmt(err, f),
+            Error::Body(err) => Debug::fmt(err, f),
+            Error::InvalidStatus(code) => write!(f, "InvalidStatus({})", code),
+            Error::Cancelled => f.write_str("request cancelled"),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Error::Curl(err) => Display::fmt(err, f),
+            Error::Multi(err) => Display::fmt(err, f),
+            Error::Body(err) => Display::fmt(err, f),
+            Error::InvalidStatus(code) => write!(f, "invalid HTTP status code {}", code),
+            Error::Cancelled => f.write_str("request cancelled"),
+        }
     }
 }
+
+impl StdError for Error {}
@@ -0,0 +1,63 @@
+use crate::url::Parts;
+use curl::easy::Easy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Idle easy handles by origin.
+///
+/// Each handle keeps its own connection cache, DNS cache and TLS sessions,
+/// so reusing it for the same origin reuses the keep-alive connection.
+pub(crate) struct Pool {
+    max_idle_per_host: usize,
+    idle: Mutex<HashMap<String, Vec<Easy>>>,
+}
+
+impl Pool {
+    pub(crate) fn new(max_idle_per_host: usize) -> Pool {
+        Pool {
+            max_idle_per_host,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// An idle handle for the origin of `url`, or a new one.
+    pub(crate) fn checkout(&self, url: &str) -> Easy {
+        let key = match key(url) {
+            Some(key) => key,
+            None => return Easy::new(),
+        };
+        let mut idle = self.idle.lock().unwrap();
+        let easy = idle.get_mut(&key).and_then(Vec::pop);
+        if idle.get(&key).is_some_and(Vec::is_empty) {
+            idle.remove(&key);
+        }
+        easy.unwrap_or_else(Easy::new)
+    }
+
+    /// Returns `easy` after performing a request to `url`, its connections
+    /// are closed if the origin already has enough idle handles.
+    pub(crate) fn checkin(&self, url: &str, mut easy: Easy) {
+        // The callbacks may refer to the finished request, so they are
+        // replaced before anything else can invoke them
+        let _ = easy.read_function(|_| Ok(0));
+        let _ = easy.write_function(|data| Ok(data.len()));
+        let _ = easy.header_function(|_| true);
+        let _ = easy.progress_function(|_, _, _, _| true);
+        easy.reset();
+
+        let key = match key(url) {
+            Some(key) => key,
+            None => return,
+        };
+        let mut idle = self.idle.lock().unwrap();
+        let handles = idle.entry(key).or_default();
+        if handles.len() < self.max_idle_per_host {
+            handles.push(easy);
+        }
+    }
+}
+
+fn key(url: &str) -> Option<String> {
+    let (scheme, host, port) = Parts::parse(url).origin()?;
+    Some(format!("{}://{}:{}", scheme, host, port.unwrap_or(0)))
+}
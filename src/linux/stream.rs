@@ -0,0 +1,186 @@
+use crate::imp::{parse_header, Error, Transfer};
+use crate::{HeaderMap, TlsInfo};
+use curl::easy::{Easy, WriteError};
+use curl::multi::{EasyHandle, Multi};
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Once this many bytes are buffered the transfer is paused until the reader
+/// catches up.
+const BUFFER_LIMIT: usize = 64 * 1024;
+
+#[derive(Default)]
+struct State {
+    body: Vec<u8>,
+    paused: bool,
+    headers: HeaderMap,
+    informational: bool,
+    headers_complete: bool,
+}
+
+/// Drives a single transfer on its own `Multi` from the calling thread, so
+/// that the body is only received as fast as it is read.
+pub(crate) struct BodyReader {
+    multi: Multi,
+    handle: Option<EasyHandle>,
+    state: Arc<Mutex<State>>,
+    transfer: Transfer,
+    result: Option<Result<(), curl::Error>>,
+}
+
+// The handles are only used through &mut self
+unsafe impl Send for BodyReader {}
+
+unsafe impl Sync for BodyReader {}
+
+impl State {
+    fn header(&mut self, input: &[u8]) -> bool {
+        if input.starts_with(b"HTTP/") {
+            // e.g. "HTTP/1.1 100 Continue"
+            self.informational = input
+                .split(|c| *c == b' ')
+                .nth(1)
+                .is_some_and(|code| code.len() == 3 && code[0] == b'1');
+        } else if input == b"\r\n" && !self.informational {
+            self.headers_complete = true;
+        }
+        parse_header(input, &mut self.headers)
+    }
+}
+
+impl BodyReader {
+    /// Starts the transfer and blocks until the final response headers were
+    /// received.
+    pub(crate) fn start(
+        mut easy: Easy,
+        transfer: Transfer,
+    ) -> Result<(u32, HeaderMap, Option<TlsInfo>, BodyReader), Error> {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let state_ = state.clone();
+        easy.write_function(move |input| {
+            let mut state = state_.lock().unwrap();
+            if state.body.len() >= BUFFER_LIMIT {
+                state.paused = true;
+                Err(WriteError::Pause)
+            } else {
+                state.body.extend_from_slice(input);
+                Ok(input.len())
+            }
+        })?;
+
+        let state_ = state.clone();
+        easy.header_function(move |input| state_.lock().unwrap().header(input))?;
+
+        let multi = Multi::new();
+        let handle = multi.add(easy)?;
+        let mut reader = BodyReader {
+            multi,
+            handle: Some(handle),
+            state,
+            transfer,
+            result: None,
+        };
+
+        loop {
+            reader.perform()?;
+            // Once the headers are complete, a failure is returned by `read`
+            // after the part of the body which was received
+            if reader.state.lock().unwrap().headers_complete {
+                break;
+            }
+            if let Some(Err(err)) = &reader.result {
+                return Err(reader.transfer.convert(err.clone()));
+            }
+            if reader.result.is_some() {
+                break;
+            }
+            reader.multi.wait(&mut [], Duration::from_secs(1))?;
+        }
+
+        let handle = reader.handle.as_mut().unwrap();
+        let status_code = handle.response_code()?;
+        let tls_info = reader.transfer.tls_info(handle.raw());
+        let headers = mem::take(&mut reader.state.lock().unwrap().headers);
+        Ok((status_code, headers, tls_info, reader))
+    }
+
+    fn perform(&mut self) -> Result<(), Error> {
+        if self.result.is_some() {
+            return Ok(());
+        }
+        self.multi.perform()?;
+        let handle = self.handle.as_ref().unwrap();
+        let mut result = None;
+        self.multi.messages(|message| {
+            if let Some(message_result) = message.result_for(handle) {
+                result = Some(message_result);
+            }
+        });
+        self.result = result;
+        Ok(())
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (read, unpause) = {
+                let mut state = self.state.lock().unwrap();
+                let read = buf.len().min(state.body.len());
+                buf[..read].copy_from_slice(&state.body[..read]);
+                state.body.drain(..read);
+                let unpause = state.paused && read > 0;
+                if unpause {
+                    state.paused = false;
+                }
+                (read, unpause)
+            };
+
+            // Unpausing may call the write function right away, so the lock
+            // must not be held
+            if unpause {
+                self.handle
+                    .as_ref()
+                    .unwrap()
+                    .unpause_write()
+                    .map_err(|err| into_io_error(Error::Curl(err)))?;
+            }
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match &self.result {
+                Some(Ok(())) => return Ok(0),
+                Some(Err(err)) => return Err(into_io_error(self.transfer.convert(err.clone()))),
+                None => {}
+            }
+
+            self.perform().map_err(into_io_error)?;
+            if self.result.is_none() && self.state.lock().unwrap().body.is_empty() {
+                self.multi
+                    .wait(&mut [], Duration::from_secs(1))
+                    .map_err(|err| into_io_error(Error::Multi(err)))?;
+            }
+        }
+    }
+}
+
+impl Drop for BodyReader {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.multi.remove(handle);
+        }
+    }
+}
+
+fn into_io_error(err: Error) -> io::Error {
+    let kind = match err.kind() {
+        crate::ErrorKind::Timeout => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, crate::Error::from(err))
+}
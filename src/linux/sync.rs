@@ -1,139 +1,83 @@
+use crate::header::parse_header_line;
+use crate::imp::pool::Pool;
+use crate::imp::stream::BodyReader;
+use crate::imp::{configure, prepare, status_code, Error, SendMutRef, Tls};
+use crate::request::{Body, Request};
+use crate::session::Config;
+use crate::{HeaderMap, Response};
 use curl::easy::Easy;
-use curl::easy::List;
-use std::collections::HashMap;
-use std::io::{Cursor, Read, Write};
 
-use crate::imp::{Error, Response, SendMutRef, SendSlice};
-use std::marker::PhantomData;
-
-pub struct Session {}
-
-pub struct RequestBuilder<'s, 'd> {
-    easy: Easy,
-    headers: List,
-    _session_marker: PhantomData<&'s Session>,
-    _data_marker: PhantomData<&'d u8>,
+pub struct Session {
+    pool: Pool,
+    tls: Tls,
 }
 
 impl Session {
-    pub fn new() -> Session {
-        Session {}
-    }
-
-    pub fn request<'s, 'd>(
-        &'s self,
-        method: &str,
-        url: &str,
-    ) -> Result<RequestBuilder<'s, 'd>, Error> {
-        RequestBuilder::new(method, url)
-    }
-}
-
-impl<'s, 'd> RequestBuilder<'s, 'd> {
-    pub fn new(method: &str, url: &str) -> Result<RequestBuilder<'s, 'd>, Error> {
-        let mut easy = Easy::new();
-        easy.url(url).map_err(Error)?;
-        match method {
-            "GET" => easy.get(true),
-            "POST" => easy.post(true),
-            "PUT" => easy.put(true),
-            _ => easy.custom_request(method),
+    pub fn new(config: &Config) -> Session {
+        Session {
+            pool: Pool::new(config.max_idle_per_host),
+            tls: Tls::new(&config.tls),
         }
-        .map_err(Error)?;
-
-        Ok(RequestBuilder {
-            easy,
-            headers: List::new(),
-            _session_marker: PhantomData,
-            _data_marker: PhantomData,
-        })
     }
 
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        self.headers
-            .append(format!("{}: {}", key, value).as_str())
-            .unwrap();
-        self
+    pub fn send(&self, request: Request) -> Result<Response, Error> {
+        let url = request.url.clone();
+        let mut easy = self.pool.checkout(&url);
+        let result = perform(&mut easy, request, &self.tls);
+        self.pool.checkin(&url, easy);
+        result
     }
 
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        let mut data = Cursor::new(data);
-        self.easy
-            .read_function(move |out| Ok(data.read(out).unwrap()))
-            .unwrap();
-        self
-    }
-
-    pub fn body_bytes(mut self, data: &'d [u8]) -> Self {
-        let mut pos = 0;
-        let data_ = SendSlice::new(data);
-
-        self.easy
-            .read_function(move |mut out| {
-                let data = unsafe { data_.deref() };
-                let written = out.write(&data[pos..]).unwrap();
-                pos += written;
-                Ok(written)
-            })
-            .unwrap();
-        self
+    /// Returns as soon as the response headers were received, the body is
+    /// transferred while it is read from the returned `Response`.
+    ///
+    /// The transfer runs on its own multi handle, which does not share the
+    /// connections of the pool.
+    pub fn send_streaming(&self, mut request: Request) -> Result<Response, Error> {
+        // The transfer outlives this call, so a borrowed body has to be copied
+        request.body = request.body.map(Body::into_owned);
+        let (easy, transfer) = prepare(request, &self.tls)?;
+
+        let (code, headers, tls_info, reader) = BodyReader::start(easy, transfer)?;
+        let mut response = Response::streaming(status_code(code)?, headers, Box::new(reader));
+        response.tls_info = tls_info;
+        Ok(response)
     }
+}
 
-    pub fn send(mut self) -> Result<Response, Error> {
-        self.easy.http_headers(self.headers).unwrap();
+fn perform(easy: &mut Easy, request: Request, tls: &Tls) -> Result<Response, Error> {
+    let transfer = configure(easy, request, tls)?;
 
-        let mut response_body = Vec::new();
-        let response_body_ = SendMutRef::new(&mut response_body);
+    let mut response_body = Vec::new();
+    let response_body_ = SendMutRef::new(&mut response_body);
 
-        self.easy
-            .write_function(move |input| {
-                let response_body = unsafe { response_body_.deref() };
-                response_body.extend_from_slice(input);
-                Ok(input.len())
-            })
-            .map_err(Error)?;
+    easy.write_function(move |input| {
+        let response_body = unsafe { response_body_.deref() };
+        response_body.extend_from_slice(input);
+        Ok(input.len())
+    })?;
 
-        let mut headers = HashMap::new();
-        let headers_ = SendMutRef(&mut headers);
-        let mut first = true;
-        self.easy
-            .header_function(move |input| {
-                parse_header(input, &mut first, unsafe { headers_.deref() })
-            })
-            .map_err(Error)?;
+    let mut headers = HeaderMap::new();
+    let headers_ = SendMutRef::new(&mut headers);
+    easy.header_function(move |input| parse_header(input, unsafe { headers_.deref() }))?;
 
-        self.easy.perform().map_err(Error)?;
+    easy.perform().map_err(|err| transfer.convert(err))?;
 
-        let status_code = self.easy.response_code().map_err(Error)?;
+    let status_code = status_code(easy.response_code()?)?;
 
-        Ok(Response {
-            status_code,
-            headers,
-            body: response_body,
-        })
-    }
+    let mut response = Response::new(status_code, headers, response_body);
+    response.tls_info = transfer.tls_info(easy.raw());
+    Ok(response)
 }
 
-pub(crate) fn parse_header(
-    input: &[u8],
-    first: &mut bool,
-    headers: &mut HashMap<String, String>,
-) -> bool {
-    if *first || input == b"\r\n" {
-        *first = false;
-        return true;
-    }
-    if let Some(seperator_pos) = input.iter().position(|x| *x == b':') {
-        let (key, value) = input.split_at(seperator_pos);
-        let key = String::from_utf8_lossy(key);
-        // Remove ": "
-        let value = value.split_at(2).1;
-        // Remove "\r\n"
-        let value = String::from_utf8_lossy(value.split_at(value.len() - 2).0);
-
-        headers.insert(key.into_owned(), value.into_owned());
+pub(crate) fn parse_header(input: &[u8], headers: &mut HeaderMap) -> bool {
+    if input.starts_with(b"HTTP/") {
+        // Status line of a new response, e.g. after "100 Continue"
+        headers.clear();
+        true
+    } else if input == b"\r\n" {
         true
     } else {
-        false
+        parse_header_line(input, headers)
     }
 }
@@ -1,235 +1,448 @@
-use block::ConcreteBlock;
-use core::borrow::Borrow;
+use crate::request::{Body, Request};
+use crate::session::Config;
+use crate::{ErrorKind, HeaderMap, Proxy, ProxyKind, Response, StatusCode, TlsConfig, TlsVersion};
+use block::{Block, ConcreteBlock};
 use core::fmt::Write;
-use objc::runtime::Object;
+use objc::declare::ClassDecl;
+use objc::runtime::{Class, Object, Sel, NO};
 use objc_foundation::{
     INSData, INSDictionary, INSString, NSData, NSDictionary, NSObject, NSString,
 };
 use objc_id::Id;
-use std::fmt::{Debug, Formatter};
-use std::marker::PhantomData;
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::Read;
 use std::ops::Deref;
+use std::os::raw::c_void;
+use std::ptr::null_mut;
 use std::sync::mpsc::sync_channel;
+use std::sync::{Mutex, Once};
 
+/// NSURLSession only configures proxies per session, so there is one for
+/// each proxy used.
 pub struct AsyncSession {
-    session: Id<NSObject>,
+    sessions: Mutex<HashMap<Option<Proxy>, Id<NSObject>>>,
+    tls: TlsConfig,
 }
 
 pub struct Session(AsyncSession);
 
-pub struct AsyncRequestBuilder<'s> {
-    session: &'s Id<NSObject>,
-    request: Id<NSObject>,
+pub struct RequestHandle {
+    task: Option<Id<NSObject>>,
 }
 
-pub struct RequestBuilder<'s, 'd> {
-    inner: AsyncRequestBuilder<'s>,
-    _data_marker: PhantomData<&'d [u8]>,
-}
+unsafe impl Send for AsyncSession {}
 
-pub struct Response {
-    data: Id<NSData>,
-    response: Id<NSObject>,
-}
+unsafe impl Sync for AsyncSession {}
 
-pub struct Headers<'a> {
-    headers: Id<NSDictionary<NSString, NSString>>,
-    _marker: PhantomData<&'a u8>,
-}
+unsafe impl Send for RequestHandle {}
 
-pub struct Error {
-    error: Id<NSObject>,
-}
+unsafe impl Sync for RequestHandle {}
 
-unsafe impl Send for Response {}
+const NS_URL_SESSION_AUTH_CHALLENGE_USE_CREDENTIAL: isize = 0;
+const NS_URL_SESSION_AUTH_CHALLENGE_PERFORM_DEFAULT_HANDLING: isize = 1;
+
+pub enum Error {
+    NS(Id<NSObject>),
+    Body(io::Error),
+    Unsupported(&'static str),
+    /// The status code of the response is not a three-digit number.
+    InvalidStatus(isize),
+}
 
 unsafe impl Send for Error {}
 
-impl AsyncSession {
-    pub fn new() -> AsyncSession {
-        unsafe {
-            let configuration: *mut Object = msg_send![
-                class!(NSURLSessionConfiguration),
-                defaultSessionConfiguration
-            ];
-            let session: *mut NSObject = msg_send![
-                class!(NSURLSession),
-                sessionWithConfiguration: configuration
-            ];
-
-            AsyncSession {
-                session: Id::from_ptr(session),
+/// Declares the session delegate, which refuses every redirect so the
+/// redirect response is passed to the completion handler.
+///
+/// A subclass additionally trusts every server certificate, for
+/// `TlsConfig::danger_accept_invalid_certs`.
+fn delegate_class(accept_invalid_certs: bool) -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        extern "C" fn will_perform_redirection(
+            _this: &Object,
+            _cmd: Sel,
+            _session: *mut Object,
+            _task: *mut Object,
+            _response: *mut Object,
+            _request: *mut Object,
+            completion_handler: *mut Object,
+        ) {
+            let completion_handler = completion_handler as *mut Block<(*mut Object,), ()>;
+            unsafe { (*completion_handler).call((null_mut(),)) }
+        }
+
+        extern "C" fn did_receive_challenge(
+            _this: &Object,
+            _cmd: Sel,
+            _session: *mut Object,
+            challenge: *mut Object,
+            completion_handler: *mut Object,
+        ) {
+            let completion_handler = completion_handler as *mut Block<(isize, *mut Object), ()>;
+            unsafe {
+                let protection_space: *mut Object = msg_send![challenge, protectionSpace];
+                let method: *mut NSString = msg_send![protection_space, authenticationMethod];
+                if (*method).as_str() == "NSURLAuthenticationMethodServerTrust" {
+                    let trust: *mut c_void = msg_send![protection_space, serverTrust];
+                    let credential: *mut Object =
+                        msg_send![class!(NSURLCredential), credentialForTrust: trust];
+                    (*completion_handler)
+                        .call((NS_URL_SESSION_AUTH_CHALLENGE_USE_CREDENTIAL, credential))
+                } else {
+                    (*completion_handler).call((
+                        NS_URL_SESSION_AUTH_CHALLENGE_PERFORM_DEFAULT_HANDLING,
+                        null_mut(),
+                    ))
+                }
             }
         }
-    }
 
-    #[inline]
-    pub fn request<'s>(
-        &'s self,
-        method: &str,
-        url: &str,
-    ) -> Result<AsyncRequestBuilder<'s>, Error> {
-        Ok(AsyncRequestBuilder::new(&self.session, method, url))
-    }
-}
+        let mut decl = ClassDecl::new("NTTPSessionDelegate", class!(NSObject)).unwrap();
+        unsafe {
+            decl.add_method(
+                sel!(URLSession:task:willPerformHTTPRedirection:newRequest:completionHandler:),
+                will_perform_redirection
+                    as extern "C" fn(
+                        &Object,
+                        Sel,
+                        *mut Object,
+                        *mut Object,
+                        *mut Object,
+                        *mut Object,
+                        *mut Object,
+                    ),
+            );
+        }
+        decl.register();
 
-impl Drop for AsyncSession {
-    fn drop(&mut self) {
+        let mut decl =
+            ClassDecl::new("NTTPInsecureSessionDelegate", class!(NTTPSessionDelegate)).unwrap();
         unsafe {
-            let _: () = msg_send![self.session, finishTasksAndInvalidate];
+            decl.add_method(
+                sel!(URLSession:didReceiveChallenge:completionHandler:),
+                did_receive_challenge
+                    as extern "C" fn(&Object, Sel, *mut Object, *mut Object, *mut Object),
+            );
         }
+        decl.register();
+    });
+    if accept_invalid_certs {
+        class!(NTTPInsecureSessionDelegate)
+    } else {
+        class!(NTTPSessionDelegate)
     }
 }
 
-impl Session {
-    #[inline]
-    pub fn new() -> Session {
-        Session(AsyncSession::new())
+fn create_session(proxy: Option<&Proxy>, tls: &TlsConfig) -> Result<Id<NSObject>, Error> {
+    if !tls.root_certificates.is_empty() {
+        return Err(Error::Unsupported("additional root certificates"));
+    }
+    if tls.identity.is_some() {
+        return Err(Error::Unsupported("client certificates"));
     }
+    if !tls.pinned_public_keys.is_empty() {
+        return Err(Error::Unsupported("public key pinning"));
+    }
+    unsafe {
+        let configuration: *mut Object = msg_send![
+            class!(NSURLSessionConfiguration),
+            defaultSessionConfiguration
+        ];
+        // An empty dictionary disables the system proxy settings
+        let proxy_dictionary = proxy_dictionary(proxy)?;
+        let _: () = msg_send![configuration, setConnectionProxyDictionary: proxy_dictionary];
+        // Cookies are only handled by the CookieJar of the session
+        let _: () = msg_send![configuration, setHTTPShouldSetCookies: NO];
+        let _: () = msg_send![configuration, setHTTPCookieStorage: null_mut::<Object>()];
+        if let Some(version) = tls.min_version {
+            // SSLProtocol of the Security framework
+            let protocol: i32 = match version {
+                TlsVersion::Tls1_0 => 4,
+                TlsVersion::Tls1_1 => 7,
+                TlsVersion::Tls1_2 => 8,
+                TlsVersion::Tls1_3 => 10,
+            };
+            let _: () = msg_send![configuration, setTLSMinimumSupportedProtocol: protocol];
+        }
 
-    #[inline]
-    pub fn request<'s, 'd>(
-        &'s self,
-        method: &str,
-        url: &str,
-    ) -> Result<RequestBuilder<'s, 'd>, Error> {
-        Ok(RequestBuilder::new(&self.0.session, method, url))
+        let delegate: *mut Object = msg_send![delegate_class(tls.accept_invalid_certs), new];
+        // The session retains its delegate
+        let session: *mut NSObject = msg_send![
+            class!(NSURLSession),
+            sessionWithConfiguration: configuration
+            delegate: delegate
+            delegateQueue: null_mut::<Object>()
+        ];
+        let _: () = msg_send![delegate, release];
+        Ok(Id::from_ptr(session))
     }
 }
 
-impl<'s> AsyncRequestBuilder<'s> {
-    fn new(session: &'s Id<NSObject>, method: &str, url: &str) -> AsyncRequestBuilder<'s> {
-        unsafe {
-            let url: *mut Object = msg_send![class!(NSURL), URLWithString: NSString::from_str(url)];
-            let uninitialized_request: *mut Object = msg_send![class!(NSMutableURLRequest), alloc];
-            let request: *mut NSObject = msg_send![uninitialized_request, initWithURL: url];
-            msg_send![request, setHTTPMethod: NSString::from_str(method)];
-            AsyncRequestBuilder {
-                session,
-                request: Id::<NSObject>::from_retained_ptr(request),
-            }
-        }
+unsafe fn proxy_dictionary(proxy: Option<&Proxy>) -> Result<*mut Object, Error> {
+    let dictionary: *mut Object = msg_send![class!(NSMutableDictionary), dictionary];
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return Ok(dictionary),
+    };
+    if proxy.credentials().is_some() {
+        return Err(Error::Unsupported("proxy credentials"));
+    }
+    let prefixes: &[&str] = match proxy.kind() {
+        // Tunnels HTTPS requests as well
+        ProxyKind::Http => &["HTTP", "HTTPS"],
+        ProxyKind::Socks5 | ProxyKind::Socks5h => &["SOCKS"],
+        _ => return Err(Error::Unsupported("an HTTPS proxy")),
+    };
+    let enable: *mut Object = msg_send![class!(NSNumber), numberWithInt: 1i32];
+    let port: *mut Object = msg_send![class!(NSNumber), numberWithInt: proxy.port() as i32];
+    let host = NSString::from_str(proxy.host());
+    for prefix in prefixes {
+        let key = |name: &str| NSString::from_str(&format!("{}{}", prefix, name));
+        let _: () = msg_send![dictionary, setObject: enable forKey: key("Enable")];
+        let _: () = msg_send![dictionary, setObject: &*host forKey: key("Proxy")];
+        let _: () = msg_send![dictionary, setObject: port forKey: key("Port")];
     }
+    Ok(dictionary)
+}
 
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        unsafe {
-            msg_send![self.request, addValue:NSString::from_str(value) forHTTPHeaderField:NSString::from_str(key)];
+impl AsyncSession {
+    pub fn new(config: &Config) -> AsyncSession {
+        AsyncSession {
+            sessions: Mutex::new(HashMap::new()),
+            tls: config.tls.clone(),
         }
-        self
     }
 
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        unsafe {
-            msg_send![self.request, setHTTPBody: NSData::from_vec(data)];
+    pub fn send<T>(&self, request: Request<'static>, callback: T) -> RequestHandle
+    where
+        T: FnOnce(Result<Response, Error>) + Send + 'static,
+    {
+        let proxy = request.proxy.for_url(&request.url).cloned();
+        match convert_request(request) {
+            Ok(request) => self.start(proxy, request, callback),
+            Err(err) => {
+                callback(Err(Error::Body(err)));
+                RequestHandle { task: None }
+            }
         }
-        self
     }
 
-    pub fn send<T>(mut self, callback: T)
+    fn start<T>(&self, proxy: Option<Proxy>, request: Id<NSObject>, callback: T) -> RequestHandle
     where
-        T: Fn(Result<Response, Error>) + Send + 'static,
+        T: FnOnce(Result<Response, Error>) + Send + 'static,
     {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = match sessions.entry(proxy) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match create_session(entry.key().as_ref(), &self.tls) {
+                Ok(session) => entry.insert(session),
+                Err(err) => {
+                    drop(sessions);
+                    callback(Err(err));
+                    return RequestHandle { task: None };
+                }
+            },
+        };
+
+        // Blocks have to be Fn, the completion handler is only invoked once
+        let callback = Cell::new(Some(callback));
         unsafe {
             let completion_handler = ConcreteBlock::new(
                 move |data: *mut NSData, response: *mut NSObject, error: *mut NSObject| {
+                    let callback = match callback.take() {
+                        Some(callback) => callback,
+                        None => return,
+                    };
                     callback(if response.is_null() {
                         let error = Id::<NSObject>::from_ptr(error);
-                        Result::Err(Error { error })
+                        Result::Err(Error::NS(error))
                     } else {
-                        let data = Id::<NSData>::from_ptr(data);
-                        let response = Id::<NSObject>::from_ptr(response);
-
-                        Result::Ok(Response { data, response })
+                        convert_response(data, response)
                     });
                 },
             );
 
-            let data_task: *mut Object = msg_send![self.session.deref(), dataTaskWithRequest: self.request completionHandler: completion_handler.copy()];
+            let data_task: *mut NSObject = msg_send![&**session, dataTaskWithRequest: request completionHandler: completion_handler.copy()];
             let _: () = msg_send![data_task, resume];
+            RequestHandle {
+                task: Some(Id::from_ptr(data_task)),
+            }
         }
     }
 }
 
-impl<'s, 'd> RequestBuilder<'s, 'd> {
-    #[inline]
-    fn new(session: &'s Id<NSObject>, method: &str, url: &str) -> RequestBuilder<'s, 'd> {
-        RequestBuilder {
-            inner: AsyncRequestBuilder::new(session, method, url),
-            _data_marker: PhantomData,
+impl Drop for AsyncSession {
+    fn drop(&mut self) {
+        for session in self.sessions.get_mut().unwrap().values() {
+            unsafe {
+                let _: () = msg_send![session.deref(), finishTasksAndInvalidate];
+            }
         }
     }
+}
 
+impl Session {
     #[inline]
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        RequestBuilder {
-            inner: self.inner.header(key, value),
-            _data_marker: PhantomData,
-        }
+    pub fn new(config: &Config) -> Session {
+        Session(AsyncSession::new(config))
+    }
+
+    pub fn send(&self, request: Request) -> Result<Response, Error> {
+        // The body is copied into the NSURLRequest
+        let proxy = request.proxy.for_url(&request.url).cloned();
+        let request = convert_request(request).map_err(Error::Body)?;
+
+        let (tx, rx) = sync_channel(1);
+        self.0
+            .start(proxy, request, move |result| tx.send(result).unwrap());
+        rx.recv().unwrap()
     }
 
+    /// NSURLSession data tasks always buffer the body
     #[inline]
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        RequestBuilder {
-            inner: self.inner.body_vec(data),
-            _data_marker: PhantomData,
-        }
+    pub fn send_streaming(&self, request: Request) -> Result<Response, Error> {
+        self.send(request)
     }
+}
 
-    pub fn body_bytes(mut self, data: &'d [u8]) -> Self {
-        unsafe {
-            let ns_data: *mut NSData = msg_send![class!(NSData), dataWithBytesNoCopy:data.as_ptr() length:data.len() freeWhenDone:false];
-            msg_send![self.inner.request, setHTTPBody: ns_data];
+impl RequestHandle {
+    /// The completion handler receives NSURLErrorCancelled
+    pub fn cancel(&self) {
+        if let Some(task) = &self.task {
+            unsafe {
+                let _: () = msg_send![task.deref(), cancel];
+            }
         }
-        self
     }
+}
 
-    pub fn send(mut self) -> Result<Response, Error> {
-        let (tx, rx) = sync_channel(1);
-        self.inner.send(move |result| tx.send(result).unwrap());
+fn convert_request(request: Request) -> Result<Id<NSObject>, io::Error> {
+    // NSURLSession only streams from an NSInputStream, so a reader is
+    // buffered instead
+    let body = match request.body {
+        Some(Body::Reader(mut reader, len)) => {
+            let mut body = Vec::with_capacity(len.unwrap_or(0) as usize);
+            reader.read_to_end(&mut body)?;
+            Some(NSData::from_vec(body))
+        }
+        Some(body) => body.as_bytes().map(|data| NSData::from_vec(data.to_vec())),
+        None => None,
+    };
+
+    unsafe {
+        let url: *mut Object =
+            msg_send![class!(NSURL), URLWithString: NSString::from_str(&request.url)];
+        let uninitialized_request: *mut Object = msg_send![class!(NSMutableURLRequest), alloc];
+        let ns_request: *mut NSObject = msg_send![uninitialized_request, initWithURL: url];
+        let ns_request = Id::<NSObject>::from_retained_ptr(ns_request);
+        let _: () =
+            msg_send![ns_request, setHTTPMethod: NSString::from_str(request.method.as_str())];
+        for (name, value) in &request.headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let _: () = msg_send![ns_request, addValue:NSString::from_str(&value) forHTTPHeaderField:NSString::from_str(name)];
+        }
+        // NSURLSession always decodes, so only the negotiation can be avoided
+        if !request.decompress && !request.headers.contains_key("Accept-Encoding") {
+            let _: () = msg_send![ns_request, setValue:NSString::from_str("identity") forHTTPHeaderField:NSString::from_str("Accept-Encoding")];
+        }
+        if let Some(body) = body {
+            let _: () = msg_send![ns_request, setHTTPBody: body];
+        }
 
-        let response = rx.recv().unwrap();
-        response
+        // NSURLRequest only has a single timeout, which behaves like an idle
+        // timeout, so the shortest limit is used
+        if let Some(timeout) = request.timeouts.shortest() {
+            let _: () = msg_send![ns_request, setTimeoutInterval: timeout.as_secs_f64()];
+        }
+        Ok(ns_request)
     }
 }
 
-impl<'a> Response {
-    pub fn status_code(&self) -> u32 {
-        unsafe { msg_send![self.response, statusCode] }
+unsafe fn convert_response(data: *mut NSData, response: *mut NSObject) -> Result<Response, Error> {
+    let code: isize = msg_send![response, statusCode];
+    let status_code = u32::try_from(code)
+        .ok()
+        .and_then(StatusCode::from_raw)
+        .ok_or(Error::InvalidStatus(code))?;
+
+    let fields: Id<NSDictionary<NSString, NSString>> =
+        Id::from_ptr(msg_send![response, allHeaderFields]);
+    let mut headers = HeaderMap::new();
+    for key in fields.keys() {
+        if let Some(value) = fields.object_for(key) {
+            headers.append(key.as_str(), value.as_str());
+        }
     }
 
-    pub fn body(&self) -> &[u8] {
-        self.data.bytes()
-    }
+    let body = if data.is_null() {
+        Vec::new()
+    } else {
+        (*data).bytes().to_vec()
+    };
 
-    pub fn headers(&'a self) -> Headers<'a> {
-        let headers: Id<NSDictionary<NSString, NSString>> =
-            unsafe { Id::from_ptr(msg_send![self.response, allHeaderFields]) };
-        Headers {
-            headers,
-            _marker: PhantomData,
-        }
-    }
+    Ok(Response::new(status_code, headers, body))
 }
 
-impl<'a> Headers<'a> {
-    pub fn list(&self) -> Vec<&str> {
-        self.headers.keys().iter().map(|key| key.as_str()).collect()
+impl Error {
+    pub(crate) fn kind(&self) -> ErrorKind {
+        let error = match self {
+            Error::NS(error) => error,
+            Error::Body(_) => return ErrorKind::BodyRead,
+            Error::Unsupported(_) => return ErrorKind::Other,
+            Error::InvalidStatus(_) => return ErrorKind::Protocol,
+        };
+        let domain: Id<NSString> = unsafe { msg_send![&**error, domain] };
+        if domain.as_str() != "NSURLErrorDomain" {
+            return ErrorKind::Other;
+        }
+        let code: isize = unsafe { msg_send![&**error, code] };
+        // See NSURLError.h
+        match code {
+            -999 => ErrorKind::Aborted,
+            -1000 | -1002 => ErrorKind::InvalidUrl,
+            -1001 => ErrorKind::Timeout,
+            -1003 | -1006 => ErrorKind::Dns,
+            -1004 | -1009 => ErrorKind::Connect,
+            -1005 => ErrorKind::Io,
+            -1007 => ErrorKind::TooManyRedirects,
+            -1011 | -1015 | -1016 | -1017 => ErrorKind::Protocol,
+            -1206..=-1200 => ErrorKind::Tls,
+            -1021 => ErrorKind::BodyRead,
+            _ => ErrorKind::Other,
+        }
     }
+}
 
-    pub fn get(&self, key: &str) -> Option<&str> {
-        self.headers
-            .object_for(NSString::from_str(key).borrow())
-            .map(NSString::as_str)
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        Debug::fmt(self, f)
     }
 }
 
+impl StdError for Error {}
+
 impl Debug for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        let domain: Id<NSString> = unsafe { msg_send![self.error, domain] };
+        let error = match self {
+            Error::NS(error) => error,
+            Error::Body(err) => return Debug::fmt(err, f),
+            Error::Unsupported(feature) => {
+                return write!(f, "{} is not supported by NSURLSession", feature)
+            }
+            Error::InvalidStatus(code) => return write!(f, "invalid HTTP status code {}", code),
+        };
+        let domain: Id<NSString> = unsafe { msg_send![&**error, domain] };
         f.write_str(domain.as_str())?;
 
         let localized_description: Id<NSString> =
-            unsafe { msg_send![self.error, localizedDescription] };
+            unsafe { msg_send![&**error, localizedDescription] };
         f.write_char(' ')?;
         f.write_str(localized_description.as_str())?;
         Ok(())
@@ -0,0 +1,151 @@
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+/// The HTTP method of a request.
+///
+/// The methods defined in RFC 7231 and RFC 5789 are available as associated
+/// constants. Extension methods can be parsed from any valid token using
+/// `str::parse` or `Method::from_bytes`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Method(Inner);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Inner {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Extension(Box<str>),
+}
+
+/// Error returned when parsing a string which is not a valid method token.
+pub struct InvalidMethod(());
+
+impl Method {
+    pub const GET: Method = Method(Inner::Get);
+    pub const HEAD: Method = Method(Inner::Head);
+    pub const POST: Method = Method(Inner::Post);
+    pub const PUT: Method = Method(Inner::Put);
+    pub const DELETE: Method = Method(Inner::Delete);
+    pub const CONNECT: Method = Method(Inner::Connect);
+    pub const OPTIONS: Method = Method(Inner::Options);
+    pub const TRACE: Method = Method(Inner::Trace);
+    pub const PATCH: Method = Method(Inner::Patch);
+
+    pub fn from_bytes(src: &[u8]) -> Result<Method, InvalidMethod> {
+        let inner = match src {
+            b"GET" => Inner::Get,
+            b"HEAD" => Inner::Head,
+            b"POST" => Inner::Post,
+            b"PUT" => Inner::Put,
+            b"DELETE" => Inner::Delete,
+            b"CONNECT" => Inner::Connect,
+            b"OPTIONS" => Inner::Options,
+            b"TRACE" => Inner::Trace,
+            b"PATCH" => Inner::Patch,
+            _ if !src.is_empty() && src.iter().all(|c| is_token_char(*c)) => {
+                // Token characters are a subset of ASCII
+                Inner::Extension(String::from_utf8_lossy(src).into())
+            }
+            _ => return Err(InvalidMethod(())),
+        };
+        Ok(Method(inner))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            Inner::Get => "GET",
+            Inner::Head => "HEAD",
+            Inner::Post => "POST",
+            Inner::Put => "PUT",
+            Inner::Delete => "DELETE",
+            Inner::Connect => "CONNECT",
+            Inner::Options => "OPTIONS",
+            Inner::Trace => "TRACE",
+            Inner::Patch => "PATCH",
+            Inner::Extension(method) => method,
+        }
+    }
+
+    /// Whether the method is safe as defined in RFC 7231, section 4.2.1.
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self.0,
+            Inner::Get | Inner::Head | Inner::Options | Inner::Trace
+        )
+    }
+
+    /// Whether the method is idempotent as defined in RFC 7231, section 4.2.2.
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self.0, Inner::Put | Inner::Delete)
+    }
+}
+
+// tchar as defined in RFC 7230, section 3.2.6
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+impl FromStr for Method {
+    type Err = InvalidMethod;
+
+    fn from_str(s: &str) -> Result<Method, InvalidMethod> {
+        Method::from_bytes(s.as_bytes())
+    }
+}
+
+impl AsRef<str> for Method {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for Method {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Method {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Default for Method {
+    fn default() -> Method {
+        Method::GET
+    }
+}
+
+impl Debug for Method {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Debug for InvalidMethod {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str("InvalidMethod")
+    }
+}
+
+impl Display for InvalidMethod {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str("invalid HTTP method")
+    }
+}
+
+impl StdError for InvalidMethod {}
@@ -0,0 +1,132 @@
+use crate::request::{Body, Request};
+use crate::url;
+use crate::{Error, HeaderMap, Method, Response};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Runs around every exchange of a session, e.g. to sign or log requests or
+/// to rewrite responses. Registered with `SessionBuilder::middleware`.
+///
+/// Both methods are called for every exchange, so again for each redirect,
+/// retry and answer to a Digest challenge. They see the request after the
+/// default headers, cookies and Authorization field were added.
+pub trait Middleware: Send + Sync {
+    /// Inspects or changes `request` before it is sent.
+    ///
+    /// Returning a response answers the request without sending it. The
+    /// middleware registered after this one are then skipped, and the
+    /// response is handled as if the server had sent it.
+    fn request(&self, request: &mut RequestParts) -> Option<Response> {
+        let _ = request;
+        None
+    }
+
+    /// Inspects or replaces the outcome of sending `request`.
+    ///
+    /// Called in reverse order of registration, only for the middleware
+    /// whose `request` was called. The body of `request` is not available.
+    fn response(
+        &self,
+        request: &RequestParts,
+        result: Result<Response, Error>,
+    ) -> Result<Response, Error> {
+        let _ = request;
+        result
+    }
+}
+
+/// The request of an exchange as passed to `Middleware`.
+pub struct RequestParts<'a, 'd>(&'a mut Request<'d>);
+
+/// The middleware of a session in order of registration.
+#[derive(Clone, Default)]
+pub(crate) struct Chain(Vec<Arc<dyn Middleware>>);
+
+impl<'a, 'd> RequestParts<'a, 'd> {
+    #[inline]
+    pub fn method(&self) -> &Method {
+        &self.0.method
+    }
+
+    #[inline]
+    pub fn set_method(&mut self, method: Method) {
+        self.0.method = method;
+    }
+
+    #[inline]
+    pub fn url(&self) -> &str {
+        &self.0.url
+    }
+
+    /// Sends the request to `url`, resolved against the current one.
+    ///
+    /// Redirects, cookies and `Response::url` then refer to the new URL.
+    pub fn set_url(&mut self, url: &str) {
+        if let Some(url) = url::resolve(&self.0.url, url) {
+            self.0.url = url;
+        }
+    }
+
+    #[inline]
+    pub fn headers(&self) -> &HeaderMap {
+        &self.0.headers
+    }
+
+    #[inline]
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.0.headers
+    }
+
+    /// The body, `None` without one or if it is streamed from a reader.
+    #[inline]
+    pub fn body(&self) -> Option<&[u8]> {
+        self.0.body.as_ref().and_then(Body::as_bytes)
+    }
+
+    /// Replaces the body, the Content-Type field is left as it is.
+    #[inline]
+    pub fn set_body(&mut self, data: Vec<u8>) {
+        self.0.body = Some(Body::Shared(Arc::new(data)));
+    }
+}
+
+impl Chain {
+    pub(crate) fn push(&mut self, middleware: Arc<dyn Middleware>) {
+        self.0.push(middleware);
+    }
+
+    /// Passes `request` to each middleware in order until one answers it.
+    ///
+    /// Returns how many were called, to be passed on to `after`, and the
+    /// answer.
+    pub(crate) fn before(&self, request: &mut Request) -> (usize, Option<Response>) {
+        let mut parts = RequestParts(request);
+        for (called, middleware) in self.0.iter().enumerate() {
+            if let Some(response) = middleware.request(&mut parts) {
+                return (called + 1, Some(response));
+            }
+        }
+        (self.0.len(), None)
+    }
+
+    /// Passes the outcome of sending `request` to the first `called`
+    /// middleware in reverse order.
+    pub(crate) fn after(
+        &self,
+        called: usize,
+        request: &mut Request,
+        mut result: Result<Response, Error>,
+    ) -> Result<Response, Error> {
+        let parts = RequestParts(request);
+        for middleware in self.0[..called].iter().rev() {
+            result = middleware.response(&parts, result);
+        }
+        result
+    }
+}
+
+impl Debug for Chain {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "[{} middleware]", self.0.len())
+    }
+}
@@ -0,0 +1,247 @@
+use crate::request::Body;
+use crate::{open_body_file, Error};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A `multipart/form-data` body as described in RFC 7578, sent with
+/// `RequestBuilder::multipart`.
+///
+/// If every part is held in memory, the body is sent with a Content-Length
+/// and can be sent again for redirects. Otherwise it is streamed, with a
+/// Content-Length only if the length of every reader is known.
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<(String, Part)>,
+}
+
+/// The content of a `Multipart` field.
+pub struct Part {
+    data: PartData,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+enum PartData {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl Multipart {
+    pub fn new() -> Multipart {
+        let random = || RandomState::new().build_hasher().finish();
+        Multipart {
+            boundary: format!("nttp-{:016x}{:016x}", random(), random()),
+            parts: Vec::new(),
+        }
+    }
+
+    /// Adds the field `name` with the content `part`.
+    pub fn part(mut self, name: &str, part: Part) -> Multipart {
+        self.parts.push((name.to_string(), part));
+        self
+    }
+
+    /// Adds the text field `name`.
+    pub fn text(self, name: &str, value: &str) -> Multipart {
+        self.part(name, Part::text(value))
+    }
+
+    /// Adds the field `name` with the file at `path`, see `Part::file`.
+    pub fn file<P: AsRef<Path>>(self, name: &str, path: P) -> Result<Multipart, Error> {
+        Ok(self.part(name, Part::file(path)?))
+    }
+
+    /// The value of the Content-Type field of the body.
+    pub(crate) fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    pub(crate) fn into_body(self) -> Body<'static> {
+        let mut segments: Vec<PartData> = Vec::new();
+        let mut pending = Vec::new();
+        for (name, part) in self.parts {
+            pending.extend_from_slice(format!("--{}\r\n", self.boundary).as_bytes());
+            pending.extend_from_slice(b"Content-Disposition: form-data; name=\"");
+            pending.extend_from_slice(escape(&name).as_bytes());
+            pending.push(b'"');
+            if let Some(filename) = &part.filename {
+                pending.extend_from_slice(b"; filename=\"");
+                pending.extend_from_slice(escape(filename).as_bytes());
+                pending.push(b'"');
+            }
+            pending.extend_from_slice(b"\r\n");
+            if let Some(content_type) = &part.content_type {
+                pending.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+            }
+            pending.extend_from_slice(b"\r\n");
+            match part.data {
+                PartData::Bytes(data) => pending.extend_from_slice(&data),
+                reader => {
+                    segments.push(PartData::Bytes(std::mem::take(&mut pending)));
+                    segments.push(reader);
+                }
+            }
+            pending.extend_from_slice(b"\r\n");
+        }
+        pending.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+
+        if segments.is_empty() {
+            return Body::Shared(Arc::new(pending));
+        }
+        segments.push(PartData::Bytes(pending));
+        let mut len = Some(0);
+        let mut reader: Box<dyn Read + Send> = Box::new(std::io::empty());
+        for segment in segments {
+            let (next, segment_len): (Box<dyn Read + Send>, _) = match segment {
+                PartData::Bytes(data) => {
+                    let data_len = data.len() as u64;
+                    (Box::new(Cursor::new(data)), Some(data_len))
+                }
+                PartData::Reader(reader, len) => (reader, len),
+            };
+            len = len
+                .zip(segment_len)
+                .map(|(len, segment_len)| len + segment_len);
+            reader = Box::new(reader.chain(next));
+        }
+        Body::Reader(reader, len)
+    }
+}
+
+impl Default for Multipart {
+    fn default() -> Multipart {
+        Multipart::new()
+    }
+}
+
+impl Part {
+    pub fn text(value: &str) -> Part {
+        Part::bytes(value.as_bytes().to_vec())
+    }
+
+    pub fn bytes(data: Vec<u8>) -> Part {
+        Part {
+            data: PartData::Bytes(data),
+            filename: None,
+            content_type: None,
+        }
+    }
+
+    /// Streams the content from `reader`, whose length is `len` if known.
+    pub fn reader<R>(reader: R, len: Option<u64>) -> Part
+    where
+        R: Read + Send + 'static,
+    {
+        Part {
+            data: PartData::Reader(Box::new(reader), len),
+            filename: None,
+            content_type: None,
+        }
+    }
+
+    /// Streams the content of the file at `path`, with its file name and a
+    /// Content-Type of `application/octet-stream`.
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<Part, Error> {
+        let path = path.as_ref();
+        let (file, len) = open_body_file(path)?;
+        let part = Part::reader(file, Some(len)).content_type("application/octet-stream");
+        Ok(match path.file_name() {
+            Some(filename) => part.filename(&filename.to_string_lossy()),
+            None => part,
+        })
+    }
+
+    /// The file name the content is sent as.
+    pub fn filename(mut self, filename: &str) -> Part {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    /// Sends the part with a Content-Type, without one it is `text/plain`.
+    pub fn content_type(mut self, content_type: &str) -> Part {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+}
+
+/// Escapes the quotes and line breaks of a parameter of the
+/// Content-Disposition field as the HTML standard does.
+fn escape(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_body(body: Body) -> (Vec<u8>, Option<u64>) {
+        let len = body.len();
+        let mut data = Vec::new();
+        match body {
+            Body::Shared(shared) => data.extend_from_slice(&shared),
+            Body::Reader(mut reader, _) => {
+                reader.read_to_end(&mut data).unwrap();
+            }
+            Body::Borrowed(_) => unreachable!(),
+        }
+        (data, len)
+    }
+
+    #[test]
+    fn encoding() {
+        let mut multipart = Multipart::new().text("title", "Report").part(
+            "data",
+            Part::bytes(b"{}".to_vec())
+                .filename("a \"b\".json")
+                .content_type("application/json"),
+        );
+        multipart.boundary = "XYZ".to_string();
+        assert_eq!(
+            multipart.content_type(),
+            "multipart/form-data; boundary=XYZ"
+        );
+        let body = multipart.into_body();
+        assert!(matches!(body, Body::Shared(_)));
+        let expected = "--XYZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\
+            \r\n\
+            Report\r\n\
+            --XYZ\r\n\
+            Content-Disposition: form-data; name=\"data\"; filename=\"a %22b%22.json\"\r\n\
+            Content-Type: application/json\r\n\
+            \r\n\
+            {}\r\n\
+            --XYZ--\r\n";
+        assert_eq!(
+            read_body(body),
+            (expected.as_bytes().to_vec(), Some(expected.len() as u64))
+        );
+
+        let mut multipart = Multipart::new()
+            .part("a", Part::reader(&b"streamed"[..], Some(8)))
+            .text("b", "text");
+        multipart.boundary = "XYZ".to_string();
+        let expected = "--XYZ\r\n\
+            Content-Disposition: form-data; name=\"a\"\r\n\
+            \r\n\
+            streamed\r\n\
+            --XYZ\r\n\
+            Content-Disposition: form-data; name=\"b\"\r\n\
+            \r\n\
+            text\r\n\
+            --XYZ--\r\n";
+        assert_eq!(
+            read_body(multipart.into_body()),
+            (expected.as_bytes().to_vec(), Some(expected.len() as u64))
+        );
+
+        let multipart = Multipart::new().part("a", Part::reader(&b"?"[..], None));
+        assert_eq!(read_body(multipart.into_body()).1, None);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::request::{Body, Request};
+use crate::{url, Error, ErrorKind, Method, Response, StatusCode};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Decides whether redirect responses are followed.
+///
+/// The default follows up to 10 redirects. Every backend uses the same
+/// policy, automatic redirects of the platform are disabled.
+#[derive(Clone)]
+pub struct RedirectPolicy(Policy);
+
+#[derive(Clone)]
+enum Policy {
+    None,
+    Limited(usize),
+    Custom(Arc<dyn Fn(&Redirect) -> bool + Send + Sync>),
+}
+
+/// A redirect about to be followed, as passed to `RedirectPolicy::custom`.
+pub struct Redirect<'a> {
+    status: StatusCode,
+    url: &'a str,
+    previous: &'a [String],
+}
+
+/// Fields which are not sent to another origin.
+const SENSITIVE_HEADERS: [&str; 3] = ["Authorization", "Proxy-Authorization", "Cookie"];
+
+/// Fields which describe the body, removed along with it.
+const BODY_HEADERS: [&str; 4] = [
+    "Content-Type",
+    "Content-Length",
+    "Content-Encoding",
+    "Transfer-Encoding",
+];
+
+impl RedirectPolicy {
+    /// Redirect responses are returned as they are.
+    pub fn none() -> RedirectPolicy {
+        RedirectPolicy(Policy::None)
+    }
+
+    /// Follows at most `max` redirects, exceeding them fails with
+    /// `ErrorKind::TooManyRedirects`.
+    pub fn limited(max: usize) -> RedirectPolicy {
+        RedirectPolicy(Policy::Limited(max))
+    }
+
+    /// Calls `policy` for every redirect, which returns whether to follow
+    /// it. If not, the redirect response is returned.
+    pub fn custom<F>(policy: F) -> RedirectPolicy
+    where
+        F: Fn(&Redirect) -> bool + Send + Sync + 'static,
+    {
+        RedirectPolicy(Policy::Custom(Arc::new(policy)))
+    }
+
+    fn follow(&self, redirect: &Redirect) -> Result<bool, Error> {
+        match &self.0 {
+            Policy::None => Ok(false),
+            Policy::Limited(max) if redirect.previous.len() > *max => Err(Error::new(
+                ErrorKind::TooManyRedirects,
+                format!("more than {} redirects", max),
+            )),
+            Policy::Limited(_) => Ok(true),
+            Policy::Custom(policy) => Ok(policy(redirect)),
+        }
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> RedirectPolicy {
+        RedirectPolicy::limited(10)
+    }
+}
+
+impl Debug for RedirectPolicy {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match &self.0 {
+            Policy::None => f.write_str("None"),
+            Policy::Limited(max) => f.debug_tuple("Limited").field(max).finish(),
+            Policy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl<'a> Redirect<'a> {
+    /// The status code of the redirect response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The URL to be requested next.
+    pub fn url(&self) -> &str {
+        self.url
+    }
+
+    /// The URLs requested so far, including the one which was redirected.
+    pub fn previous(&self) -> &[String] {
+        self.previous
+    }
+}
+
+/// The request following `response` to `request`, `None` if the response is
+/// final.
+///
+/// `request` is the head of the previous request, `body` its body if there
+/// was one, which is `None` if it cannot be sent again.
+pub(crate) fn next_request<'d>(
+    policy: &RedirectPolicy,
+    mut request: Request<'d>,
+    body: Option<Option<Body<'d>>>,
+    response: &Response,
+    previous: &[String],
+) -> Result<Option<Request<'d>>, Error> {
+    let status = response.status_code();
+    let keep_method = match status.as_u16() {
+        301 | 302 => request.method != Method::POST,
+        303 => request.method == Method::HEAD,
+        307 | 308 => true,
+        _ => return Ok(None),
+    };
+    let url = match response
+        .headers()
+        .get("Location")
+        .and_then(|location| url::resolve(&request.url, location))
+    {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    if keep_method {
+        match body {
+            Some(Some(body)) => request.body = Some(body),
+            // A streamed body cannot be sent again
+            Some(None) => return Ok(None),
+            None => {}
+        }
+    } else {
+        request.method = Method::GET;
+        for name in BODY_HEADERS {
+            request.headers.remove(name);
+        }
+    }
+
+    let redirect = Redirect {
+        status,
+        url: &url,
+        previous,
+    };
+    if !policy.follow(&redirect)? {
+        return Ok(None);
+    }
+
+    if !url::same_origin(&request.url, &url) {
+        for name in SENSITIVE_HEADERS {
+            request.headers.remove(name);
+        }
+        request.digest_auth = None;
+    }
+    request.url = url;
+    Ok(Some(request))
+}
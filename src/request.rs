@@ -0,0 +1,99 @@
+use crate::auth::Credentials;
+use crate::proxy::Proxies;
+use crate::{HeaderMap, Method, Timeouts};
+use std::io::Read;
+use std::sync::Arc;
+
+/// A request as described through the builders, which the backends execute
+/// as a single exchange.
+pub(crate) struct Request<'d> {
+    pub(crate) method: Method,
+    pub(crate) url: String,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Option<Body<'d>>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) proxy: Proxies,
+    /// Whether to negotiate a Content-Encoding and decode the response.
+    pub(crate) decompress: bool,
+    /// Answers Digest challenges, see `auth::digest_authorization`.
+    pub(crate) digest_auth: Option<Credentials>,
+}
+
+pub(crate) enum Body<'d> {
+    Borrowed(&'d [u8]),
+    Shared(Arc<Vec<u8>>),
+    Reader(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl<'d> Request<'d> {
+    pub(crate) fn new(
+        method: Method,
+        url: String,
+        timeouts: Timeouts,
+        proxy: Proxies,
+    ) -> Request<'d> {
+        Request {
+            method,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+            timeouts,
+            proxy,
+            decompress: true,
+            digest_auth: None,
+        }
+    }
+
+    /// A copy of everything but the body.
+    pub(crate) fn clone_head(&self) -> Request<'d> {
+        Request {
+            method: self.method.clone(),
+            url: self.url.clone(),
+            headers: self.headers.clone(),
+            body: None,
+            timeouts: self.timeouts,
+            proxy: self.proxy.clone(),
+            decompress: self.decompress,
+            digest_auth: self.digest_auth.clone(),
+        }
+    }
+}
+
+impl<'d> Body<'d> {
+    /// `None` if the length is unknown.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn len(&self) -> Option<u64> {
+        match self {
+            Body::Borrowed(data) => Some(data.len() as u64),
+            Body::Shared(data) => Some(data.len() as u64),
+            Body::Reader(_, len) => *len,
+        }
+    }
+
+    /// The body, if it is held in memory.
+    pub(crate) fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Borrowed(data) => Some(data),
+            Body::Shared(data) => Some(data),
+            Body::Reader(..) => None,
+        }
+    }
+
+    /// A body which can be sent once more, `None` for readers.
+    pub(crate) fn try_clone(&self) -> Option<Body<'d>> {
+        match self {
+            Body::Borrowed(data) => Some(Body::Borrowed(data)),
+            Body::Shared(data) => Some(Body::Shared(data.clone())),
+            Body::Reader(..) => None,
+        }
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn into_owned(self) -> Body<'static> {
+        match self {
+            Body::Borrowed(data) => Body::Shared(Arc::new(data.to_vec())),
+            Body::Shared(data) => Body::Shared(data),
+            Body::Reader(reader, len) => Body::Reader(reader, len),
+        }
+    }
+}
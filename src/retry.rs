@@ -0,0 +1,261 @@
+use crate::request::{Body, Request};
+use crate::{cookie, Error, Response, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Decides whether failed requests are sent again, and when.
+///
+/// The default sends every request once. Policies created with
+/// `RetryPolicy::new` send idempotent requests again after errors which are
+/// likely transient, see `Error::is_retryable`, and after the responses 408
+/// Request Timeout, 429 Too Many Requests, 502 Bad Gateway, 503 Service
+/// Unavailable and 504 Gateway Timeout. They wait with an exponential
+/// backoff, or as long as the Retry-After field of the response asks.
+///
+/// Every hop of a redirected request is retried on its own, and the
+/// timeouts apply to each attempt. Requests with a body streamed from a
+/// reader are never retried.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    idempotent_only: bool,
+    retryable: Option<Arc<RetryableFn>>,
+}
+
+type RetryableFn = dyn Fn(Result<&Response, &Error>) -> bool + Send + Sync;
+
+impl RetryPolicy {
+    /// Sends every request once.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(1)
+    }
+
+    /// Sends a request up to `max_attempts` times, waiting 100 ms before
+    /// the second attempt and twice as long before each further one, up to
+    /// 10 s.
+    pub fn new(max_attempts: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+            idempotent_only: true,
+            retryable: None,
+        }
+    }
+
+    /// Waits `initial` before the second attempt, doubling the delay for
+    /// each further one up to `max`.
+    ///
+    /// A Retry-After field asking to wait longer than `max` is not honored,
+    /// the response is returned instead.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Whether to wait a random time between half and all of each delay,
+    /// so that many clients do not retry at once. Enabled by default.
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Whether only requests with an idempotent method are retried, enabled
+    /// by default. A POST which failed may have been processed anyway.
+    pub fn idempotent_only(mut self, idempotent_only: bool) -> RetryPolicy {
+        self.idempotent_only = idempotent_only;
+        self
+    }
+
+    /// Calls `retryable` with the outcome of each attempt, which returns
+    /// whether the request is sent again. It replaces the default choice of
+    /// errors and status codes.
+    pub fn retry_if<F>(mut self, retryable: F) -> RetryPolicy
+    where
+        F: Fn(Result<&Response, &Error>) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Some(Arc::new(retryable));
+        self
+    }
+
+    /// The delay before sending `request` once more after the `attempts`th
+    /// attempt ended with `result`, `None` if it is final.
+    ///
+    /// `request` is the head of the request, `body` its body as for
+    /// `redirect::next_request`.
+    pub(crate) fn delay(
+        &self,
+        request: &Request,
+        body: &Option<Option<Body>>,
+        result: Result<&Response, &Error>,
+        attempts: usize,
+    ) -> Option<Duration> {
+        if attempts >= self.max_attempts || matches!(body, Some(None)) {
+            return None;
+        }
+        if self.idempotent_only && !request.method.is_idempotent() {
+            return None;
+        }
+        let retryable = match (&self.retryable, result) {
+            (Some(retryable), result) => retryable(result),
+            (None, Ok(response)) => is_retryable_status(response.status_code()),
+            (None, Err(err)) => err.is_retryable(),
+        };
+        if !retryable {
+            return None;
+        }
+        match result.ok().and_then(retry_after) {
+            Some(delay) if delay > self.max_backoff => None,
+            Some(delay) => Some(delay),
+            None => Some(self.backoff_delay(attempts)),
+        }
+    }
+
+    fn backoff_delay(&self, attempts: usize) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1) as u32);
+        let delay = self
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+        if !self.jitter {
+            return delay;
+        }
+        let random = RandomState::new().build_hasher().finish();
+        delay / 2 + (delay / 2).mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::none()
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("idempotent_only", &self.idempotent_only)
+            .field("custom", &self.retryable.is_some())
+            .finish()
+    }
+}
+
+/// Whether a response with `status` is likely to succeed when requested
+/// again.
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// The delay the Retry-After field of `response` asks for, given in
+/// seconds or as date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get("Retry-After")?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = cookie::parse_date(value)?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Proxies;
+    use crate::{HeaderMap, Method, Timeouts};
+
+    fn request(method: Method) -> Request<'static> {
+        Request::new(method, String::new(), Timeouts::default(), Proxies::None)
+    }
+
+    fn response(status: u16, retry_after: Option<&str>) -> Response {
+        let mut headers = HeaderMap::new();
+        if let Some(retry_after) = retry_after {
+            headers.insert("Retry-After", retry_after);
+        }
+        Response::new(StatusCode::from_u16(status).unwrap(), headers, Vec::new())
+    }
+
+    #[test]
+    fn delays() {
+        let policy = RetryPolicy::new(4)
+            .backoff(Duration::from_secs(1), Duration::from_secs(3))
+            .jitter(false);
+        let get = request(Method::GET);
+        let unavailable = response(503, None);
+        let delays: Vec<_> = (1..=4)
+            .map(|attempts| policy.delay(&get, &None, Ok(&unavailable), attempts))
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(3)),
+                None,
+            ]
+        );
+
+        assert_eq!(policy.delay(&get, &None, Ok(&response(500, None)), 1), None);
+        assert_eq!(
+            policy.delay(&request(Method::POST), &None, Ok(&unavailable), 1),
+            None
+        );
+        assert_eq!(policy.delay(&get, &Some(None), Ok(&unavailable), 1), None);
+        assert_eq!(
+            policy.delay(&get, &None, Ok(&response(429, Some("2"))), 1),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy.delay(&get, &None, Ok(&response(429, Some("60"))), 1),
+            None
+        );
+        assert_eq!(
+            policy.delay(
+                &get,
+                &None,
+                Ok(&response(503, Some("Wed, 21 Oct 2015 07:28:00 GMT"))),
+                1
+            ),
+            Some(Duration::ZERO)
+        );
+
+        let jittered = RetryPolicy::new(2).backoff(Duration::from_secs(2), Duration::from_secs(2));
+        let delay = jittered.delay(&get, &None, Ok(&unavailable), 1).unwrap();
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+
+        let custom = RetryPolicy::new(2)
+            .idempotent_only(false)
+            .retry_if(|result| matches!(result, Ok(response) if response.status_code() == 500));
+        let post = request(Method::POST);
+        assert!(custom
+            .delay(&post, &None, Ok(&response(500, None)), 1)
+            .is_some());
+        assert_eq!(custom.delay(&post, &None, Ok(&unavailable), 1), None);
+        assert_eq!(
+            RetryPolicy::default().delay(&get, &None, Ok(&unavailable), 1),
+            None
+        );
+    }
+}
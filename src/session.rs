@@ -0,0 +1,271 @@
+use crate::auth;
+use crate::auth::{Credentials, DigestCache};
+use crate::middleware::Chain;
+use crate::proxy::Proxies;
+use crate::request::Request;
+use crate::{
+    imp, url, AsyncSession, CookieJar, Error, ErrorKind, HeaderMap, HeaderValue, Middleware, Proxy,
+    RedirectPolicy, RetryPolicy, Session, Timeouts, TlsConfig,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Creates a `Session` or `AsyncSession` with defaults for all of its
+/// requests, which can be overridden by each request builder.
+#[derive(Clone, Debug, Default)]
+pub struct SessionBuilder {
+    config: Config,
+}
+
+/// The configuration shared by all requests of a session.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub(crate) headers: HeaderMap,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) redirect_policy: RedirectPolicy,
+    pub(crate) retry_policy: RetryPolicy,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) max_idle_per_host: usize,
+    pub(crate) base_url: Option<String>,
+    pub(crate) proxy: Proxies,
+    pub(crate) tls: TlsConfig,
+    pub(crate) cookie_jar: Option<Arc<CookieJar>>,
+    pub(crate) digest_auth: Option<Credentials>,
+    pub(crate) decompress: bool,
+    pub(crate) middleware: Chain,
+}
+
+impl SessionBuilder {
+    pub fn new() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    /// Sets the User-Agent sent with every request.
+    pub fn user_agent(self, user_agent: &str) -> SessionBuilder {
+        self.default_header("User-Agent", user_agent)
+    }
+
+    /// Sends `key` with every request, unless a request sets the field
+    /// itself. Setting a field again replaces its value.
+    pub fn default_header<V: Into<HeaderValue>>(mut self, key: &str, value: V) -> SessionBuilder {
+        self.config.headers.insert(key, value);
+        self
+    }
+
+    /// Replaces all default header fields.
+    pub fn default_headers(mut self, headers: HeaderMap) -> SessionBuilder {
+        self.config.headers = headers;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> SessionBuilder {
+        self.config.timeouts = timeouts;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.config.timeouts.request = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.config.timeouts.connect = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.config.timeouts.idle = Some(timeout);
+        self
+    }
+
+    pub fn redirect_policy(mut self, policy: RedirectPolicy) -> SessionBuilder {
+        self.config.redirect_policy = policy;
+        self
+    }
+
+    /// Sends failed requests again as `policy` decides, by default they are
+    /// not.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> SessionBuilder {
+        self.config.retry_policy = policy;
+        self
+    }
+
+    /// Resolves the URLs passed to `request` against `url` as described in
+    /// RFC 3986, so they can be relative like `users?id=1` or `/status`.
+    ///
+    /// The last path segment of `url` is replaced by relative paths unless
+    /// `url` ends with a slash. Fails if `url` is not absolute.
+    pub fn base_url(mut self, url: &str) -> Result<SessionBuilder, Error> {
+        if !url::is_absolute(url) {
+            return Err(Error::new(
+                ErrorKind::InvalidUrl,
+                format!("base URL is not absolute: {}", url),
+            ));
+        }
+        self.config.base_url = Some(url.to_string());
+        Ok(self)
+    }
+
+    /// Sends all requests through `proxy`, unless a request overrides it.
+    ///
+    /// Without a proxy requests are sent directly, environment variables are
+    /// only considered after `proxy_from_env`.
+    pub fn proxy(mut self, proxy: Proxy) -> SessionBuilder {
+        self.config.proxy = Proxies::All(proxy);
+        self
+    }
+
+    /// Uses the proxies from the `http_proxy`, `https_proxy`, `all_proxy` and
+    /// `no_proxy` environment variables, in lower or upper case. They are
+    /// read once by this call.
+    pub fn proxy_from_env(mut self) -> SessionBuilder {
+        self.config.proxy = Proxies::from_env();
+        self
+    }
+
+    /// Authenticates every request with the Basic scheme, unless a request
+    /// sets the Authorization field itself.
+    pub fn basic_auth(self, username: &str, password: &str) -> SessionBuilder {
+        self.default_header("Authorization", auth::basic(username, password))
+    }
+
+    /// Authenticates every request with `token` as Bearer token, unless a
+    /// request sets the Authorization field itself.
+    pub fn bearer_auth(self, token: &str) -> SessionBuilder {
+        self.default_header("Authorization", format!("Bearer {}", token))
+    }
+
+    /// Answers Digest challenges of the server with `username` and
+    /// `password`, unless a request sets other credentials.
+    ///
+    /// A request answered with 401 Unauthorized is sent once more with the
+    /// answer to the challenge, unless the request sets the Authorization
+    /// field itself or its body was streamed from a reader. The session
+    /// remembers the last challenge of each origin and answers it in advance
+    /// for later requests, which then need no second round trip and may
+    /// stream their body. A 401 to such a request is only answered if the
+    /// server reports the nonce as stale. The credentials are not sent along
+    /// redirects to another origin.
+    pub fn digest_auth(mut self, username: &str, password: &str) -> SessionBuilder {
+        self.config.digest_auth = Some(Credentials::new(username, password));
+        self
+    }
+
+    /// Whether to ask for compressed responses and decode them, enabled by
+    /// default.
+    ///
+    /// The body is returned decoded, while the Content-Encoding and
+    /// Content-Length fields are kept as received. Which encodings are
+    /// supported depends on the backend: libcurl on Linux supports gzip and
+    /// deflate, and brotli and zstd if it was built with them, macOS gzip,
+    /// deflate and brotli, Windows gzip and deflate.
+    pub fn decompress(mut self, enabled: bool) -> SessionBuilder {
+        self.config.decompress = enabled;
+        self
+    }
+
+    /// Replaces the TLS settings, e.g. to trust a private CA or to present
+    /// a client certificate.
+    pub fn tls(mut self, tls: TlsConfig) -> SessionBuilder {
+        self.config.tls = tls;
+        self
+    }
+
+    /// Stores the cookies set by responses in `jar` and sends them with
+    /// later requests. Without a jar, cookies are neither stored nor sent.
+    pub fn cookie_jar(mut self, jar: Arc<CookieJar>) -> SessionBuilder {
+        self.config.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Runs `middleware` around every exchange, after the middleware added
+    /// before it.
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> SessionBuilder {
+        self.config.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Limits the idle connections a `Session` keeps open to each host for
+    /// reuse, 0 closes every connection after its request.
+    ///
+    /// Defaults to 4. The asynchronous backends pool connections themselves.
+    pub fn max_idle_connections_per_host(mut self, max: usize) -> SessionBuilder {
+        self.config.max_idle_per_host = max;
+        self
+    }
+
+    pub fn build(self) -> Session {
+        Session {
+            inner: imp::Session::new(&self.config),
+            config: self.config,
+            digest: DigestCache::default(),
+        }
+    }
+
+    pub fn build_async(self) -> AsyncSession {
+        AsyncSession {
+            inner: Arc::new(imp::AsyncSession::new(&self.config)),
+            config: self.config,
+            timer: Arc::default(),
+            digest: Arc::default(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            headers: HeaderMap::new(),
+            timeouts: Timeouts::default(),
+            redirect_policy: RedirectPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            max_idle_per_host: 4,
+            base_url: None,
+            proxy: Proxies::None,
+            tls: TlsConfig::default(),
+            cookie_jar: None,
+            digest_auth: None,
+            decompress: true,
+            middleware: Chain::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Resolves `url` against the base URL, without one it has to be
+    /// absolute.
+    pub(crate) fn resolve_url(&self, url: &str) -> Result<String, Error> {
+        let resolved = match &self.base_url {
+            Some(base) => url::resolve(base, url),
+            None if url::is_absolute(url) => Some(url.to_string()),
+            None => None,
+        };
+        resolved.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidUrl,
+                format!("relative URL without a base: {}", url),
+            )
+        })
+    }
+
+    /// Adds the default header fields which `request` does not set, in front
+    /// of its own, and the Digest credentials unless it has some.
+    pub(crate) fn apply_defaults(&self, request: &mut Request) {
+        if request.digest_auth.is_none() {
+            request.digest_auth = self.digest_auth.clone();
+        }
+        if self.headers.is_empty() {
+            return;
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if !request.headers.contains_key(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        for (name, value) in &request.headers {
+            headers.append(name, value.clone());
+        }
+        request.headers = headers;
+    }
+}
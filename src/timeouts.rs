@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// Limits for the duration of a request, `None` means no limit.
+///
+/// Set as defaults for all requests of a session with `SessionBuilder` or
+/// `Session::set_timeouts`, individual limits can be overridden per
+/// request. An elapsed timeout is reported as an error of kind
+/// `ErrorKind::Timeout`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    /// Time to establish the connection, including name resolution and the
+    /// TLS handshake.
+    pub connect: Option<Duration>,
+    /// Total time of the request, from connecting until the body was
+    /// received completely.
+    pub request: Option<Duration>,
+    /// Time without any data being sent or received.
+    pub idle: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new() -> Timeouts {
+        Timeouts::default()
+    }
+
+    pub fn connect(mut self, timeout: Duration) -> Timeouts {
+        self.connect = Some(timeout);
+        self
+    }
+
+    pub fn request(mut self, timeout: Duration) -> Timeouts {
+        self.request = Some(timeout);
+        self
+    }
+
+    pub fn idle(mut self, timeout: Duration) -> Timeouts {
+        self.idle = Some(timeout);
+        self
+    }
+
+    /// The shortest of all limits, for backends which only support a single
+    /// timeout.
+    #[cfg_attr(not(any(target_os = "macos", target_os = "ios")), allow(dead_code))]
+    pub(crate) fn shortest(&self) -> Option<Duration> {
+        [self.connect, self.request, self.idle]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Runs tasks after a delay on a single thread, which is started with the
+/// first task and ends once the timer was dropped and no task is left.
+#[derive(Default)]
+pub(crate) struct Timer(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    wakeup: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Ordered by deadline, then by id.
+    tasks: BTreeMap<(Instant, u64), Task>,
+    next_id: u64,
+    running: bool,
+    dropped: bool,
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Starts another thread if a task panicked, so the remaining ones still run.
+struct Restart(Arc<Shared>);
+
+impl Timer {
+    /// Runs `task` once `delay` elapsed. Returns its id for `expedite`.
+    pub(crate) fn schedule<F>(&self, delay: Duration, task: F) -> u64
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.0.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state
+            .tasks
+            .insert((Instant::now() + delay, id), Box::new(task));
+        if !state.running {
+            state.running = true;
+            Shared::start(self.0.clone());
+        }
+        self.0.wakeup.notify_one();
+        id
+    }
+
+    /// Runs the task `id` right away, unless it already ran.
+    pub(crate) fn expedite(&self, id: u64) {
+        let mut state = self.0.state.lock().unwrap();
+        let key = state.tasks.keys().find(|key| key.1 == id).copied();
+        if let Some(key) = key {
+            let task = state.tasks.remove(&key).unwrap();
+            state.tasks.insert((Instant::now(), id), task);
+            self.0.wakeup.notify_one();
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().dropped = true;
+        self.0.wakeup.notify_one();
+    }
+}
+
+impl Shared {
+    fn start(shared: Arc<Shared>) {
+        thread::spawn(move || Shared::run(shared));
+    }
+
+    fn run(shared: Arc<Shared>) {
+        let restart = Restart(shared);
+        let shared = &restart.0;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            state = match state.tasks.keys().next().copied() {
+                Some(key) if key.0 <= now => {
+                    let task = state.tasks.remove(&key).unwrap();
+                    // The task may schedule another one
+                    drop(state);
+                    task();
+                    shared.state.lock().unwrap()
+                }
+                Some((deadline, _)) => shared.wakeup.wait_timeout(state, deadline - now).unwrap().0,
+                None if state.dropped => {
+                    state.running = false;
+                    return;
+                }
+                None => shared.wakeup.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Drop for Restart {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            Shared::start(self.0.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn order() {
+        let timer = Timer::default();
+        let (sender, receiver) = channel();
+        for (delay, value) in [(30, 3), (10, 1), (20, 2)] {
+            let sender = sender.clone();
+            timer.schedule(Duration::from_millis(delay), move || {
+                sender.send(value).unwrap()
+            });
+        }
+        let sender_ = sender.clone();
+        let id = timer.schedule(Duration::from_secs(60), move || sender_.send(0).unwrap());
+        timer.expedite(id);
+        let values: Vec<_> = receiver.iter().take(4).collect();
+        assert_eq!(values, [0, 1, 2, 3]);
+
+        // A panicking task does not stop the others
+        timer.schedule(Duration::ZERO, || panic!("task failed"));
+        timer.schedule(Duration::from_millis(10), move || sender.send(4).unwrap());
+        assert_eq!(receiver.recv().unwrap(), 4);
+    }
+}
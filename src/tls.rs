@@ -0,0 +1,191 @@
+use crate::{Error, ErrorKind};
+use std::fmt::{Debug, Formatter};
+
+/// TLS settings of a session, see `SessionBuilder::tls`.
+///
+/// The defaults verify the server against the root certificates of the
+/// system and leave the protocol versions to the backend. Not every backend
+/// supports every setting, unsupported ones fail when a request is sent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub(crate) root_certificates: Vec<Certificate>,
+    pub(crate) identity: Option<Identity>,
+    pub(crate) min_version: Option<TlsVersion>,
+    pub(crate) accept_invalid_certs: bool,
+    pub(crate) pinned_public_keys: Vec<[u8; 32]>,
+    pub(crate) collect_info: bool,
+}
+
+/// One or more X.509 certificates.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub(crate) pem: Vec<u8>,
+}
+
+/// A client certificate with its private key, presented to servers which
+/// request one.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub(crate) certificate: Vec<u8>,
+    pub(crate) key: Vec<u8>,
+}
+
+/// The TLS parameters of the connection a response was received on, see
+/// `Response::tls_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub(crate) peer_certificates: Vec<Vec<u8>>,
+    pub(crate) version: Option<TlsVersion>,
+    pub(crate) cipher: Option<String>,
+}
+
+/// A version of the TLS protocol.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2,
+    Tls1_3,
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    /// Trusts `certificate` in addition to the root certificates of the
+    /// system.
+    ///
+    /// On Linux, those are read from the CA bundle of the system when the
+    /// session is built. If it cannot be read, HTTPS requests fail with
+    /// `ErrorKind::Tls` rather than trusting only `certificate`.
+    pub fn add_root_certificate(mut self, certificate: Certificate) -> TlsConfig {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Authenticates with `identity` if a server requests a client
+    /// certificate.
+    pub fn identity(mut self, identity: Identity) -> TlsConfig {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Refuses to connect with a protocol version older than `version`.
+    pub fn min_version(mut self, version: TlsVersion) -> TlsConfig {
+        self.min_version = Some(version);
+        self
+    }
+
+    /// Disables the verification of the server's certificate and host name.
+    ///
+    /// Anyone on the network path can then read and modify the requests,
+    /// only use this for local development.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> TlsConfig {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Only connects to servers whose public key has the SHA-256 digest
+    /// `sha256`, computed over its DER encoded SubjectPublicKeyInfo.
+    ///
+    /// Calling this again adds another key, e.g. a backup key, the server
+    /// has to match one of them. The certificate is still verified.
+    pub fn pin_public_key(mut self, sha256: [u8; 32]) -> TlsConfig {
+        self.pinned_public_keys.push(sha256);
+        self
+    }
+
+    /// Records the peer certificates, protocol version and cipher of each
+    /// connection, returned by `Response::tls_info`.
+    ///
+    /// On Linux this enables the progress callback of libcurl for every
+    /// transfer, which reads the version and cipher from the TLS session.
+    pub fn tls_info(mut self, collect: bool) -> TlsConfig {
+        self.collect_info = collect;
+        self
+    }
+}
+
+impl Certificate {
+    /// Reads the PEM encoded certificates in `pem`, each starting with
+    /// `-----BEGIN CERTIFICATE-----`.
+    pub fn from_pem(pem: &[u8]) -> Result<Certificate, Error> {
+        if !contains(pem, b"-----BEGIN CERTIFICATE-----") {
+            return Err(Error::new(
+                ErrorKind::Tls,
+                "no PEM encoded certificate found",
+            ));
+        }
+        Ok(Certificate { pem: pem.to_vec() })
+    }
+}
+
+impl Identity {
+    /// Combines the PEM encoded `certificate`, which may be followed by its
+    /// intermediate certificates, with its PEM encoded private `key`.
+    pub fn from_pem(certificate: &[u8], key: &[u8]) -> Result<Identity, Error> {
+        if !contains(certificate, b"-----BEGIN CERTIFICATE-----") {
+            return Err(Error::new(
+                ErrorKind::Tls,
+                "no PEM encoded client certificate found",
+            ));
+        }
+        if !contains(key, b"-----BEGIN ") || !contains(key, b"PRIVATE KEY-----") {
+            return Err(Error::new(
+                ErrorKind::Tls,
+                "no PEM encoded private key found",
+            ));
+        }
+        Ok(Identity {
+            certificate: certificate.to_vec(),
+            key: key.to_vec(),
+        })
+    }
+}
+
+impl TlsInfo {
+    /// The DER encoded certificates sent by the server, its own first,
+    /// followed by the intermediate certificates.
+    pub fn peer_certificates(&self) -> &[Vec<u8>] {
+        &self.peer_certificates
+    }
+
+    /// The negotiated protocol version, `None` if it is not one of
+    /// `TlsVersion`.
+    ///
+    /// On Linux it is read from the TLS session of libcurl, which is only
+    /// understood if libcurl uses OpenSSL. With other TLS backends the
+    /// version and the cipher are always `None`.
+    pub fn version(&self) -> Option<TlsVersion> {
+        self.version
+    }
+
+    /// The name of the negotiated cipher suite, e.g.
+    /// `TLS_AES_256_GCM_SHA384`. Like the version, only known with OpenSSL.
+    pub fn cipher(&self) -> Option<&str> {
+        self.cipher.as_deref()
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+impl Debug for Certificate {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("Certificate")
+            .field("len", &self.pem.len())
+            .finish()
+    }
+}
+
+// Never prints the private key
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("Identity").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,307 @@
+//! Just enough of RFC 3986 to resolve references and compare origins, the
+//! backends do the actual parsing.
+
+/// The components of a URI reference, see RFC 3986, Appendix B.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Parts<'a> {
+    pub(crate) scheme: Option<&'a str>,
+    pub(crate) authority: Option<&'a str>,
+    pub(crate) path: &'a str,
+    pub(crate) query: Option<&'a str>,
+    pub(crate) fragment: Option<&'a str>,
+}
+
+impl<'a> Parts<'a> {
+    pub(crate) fn parse(reference: &'a str) -> Parts<'a> {
+        let (rest, fragment) = match reference.find('#') {
+            Some(pos) => (&reference[..pos], Some(&reference[pos + 1..])),
+            None => (reference, None),
+        };
+        let (rest, query) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+        let (scheme, rest) = match rest.find([':', '/']) {
+            Some(pos) if rest.as_bytes()[pos] == b':' && is_scheme(&rest[..pos]) => {
+                (Some(&rest[..pos]), &rest[pos + 1..])
+            }
+            _ => (None, rest),
+        };
+        let (authority, path) = match rest.strip_prefix("//") {
+            Some(rest) => match rest.find('/') {
+                Some(pos) => (Some(&rest[..pos]), &rest[pos..]),
+                None => (Some(rest), ""),
+            },
+            None => (None, rest),
+        };
+        Parts {
+            scheme,
+            authority,
+            path,
+            query,
+            fragment,
+        }
+    }
+
+    /// `(scheme, host, port)` in lowercase, with the default port of the
+    /// scheme if none is given.
+    pub(crate) fn origin(&self) -> Option<(String, String, Option<u16>)> {
+        let scheme = self.scheme?.to_ascii_lowercase();
+        let authority = self.authority?;
+        let host_port = match authority.rfind('@') {
+            Some(pos) => &authority[pos + 1..],
+            None => authority,
+        };
+        // The port follows the last colon, unless it is part of an IPv6 literal
+        let (host, port) = match host_port.rfind(':') {
+            Some(pos) if !host_port[pos..].contains(']') => {
+                (&host_port[..pos], &host_port[pos + 1..])
+            }
+            _ => (host_port, ""),
+        };
+        let port = if port.is_empty() {
+            default_port(&scheme)
+        } else {
+            Some(port.parse().ok()?)
+        };
+        Some((scheme, host.to_ascii_lowercase(), port))
+    }
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        _ => None,
+    }
+}
+
+/// Whether `url` is absolute, i.e. has a scheme.
+pub(crate) fn is_absolute(url: &str) -> bool {
+    Parts::parse(url).scheme.is_some()
+}
+
+/// Resolves `reference` against the absolute URL `base`, see RFC 3986,
+/// Section 5.2.
+pub(crate) fn resolve(base: &str, reference: &str) -> Option<String> {
+    let base = Parts::parse(base);
+    let reference = Parts::parse(reference);
+    base.scheme?;
+
+    let (scheme, authority, path, query);
+    if reference.scheme.is_some() {
+        scheme = reference.scheme;
+        authority = reference.authority;
+        path = remove_dot_segments(reference.path);
+        query = reference.query;
+    } else {
+        scheme = base.scheme;
+        if reference.authority.is_some() {
+            authority = reference.authority;
+            path = remove_dot_segments(reference.path);
+            query = reference.query;
+        } else {
+            authority = base.authority;
+            if reference.path.is_empty() {
+                path = base.path.to_string();
+                query = reference.query.or(base.query);
+            } else {
+                path = if reference.path.starts_with('/') {
+                    remove_dot_segments(reference.path)
+                } else {
+                    remove_dot_segments(&merge(&base, reference.path))
+                };
+                query = reference.query;
+            }
+        }
+    }
+
+    let mut target = String::new();
+    if let Some(scheme) = scheme {
+        target.push_str(scheme);
+        target.push(':');
+    }
+    if let Some(authority) = authority {
+        target.push_str("//");
+        target.push_str(authority);
+    }
+    target.push_str(&path);
+    if let Some(query) = query {
+        target.push('?');
+        target.push_str(query);
+    }
+    if let Some(fragment) = reference.fragment {
+        target.push('#');
+        target.push_str(fragment);
+    }
+    Some(target)
+}
+
+fn merge(base: &Parts, path: &str) -> String {
+    if base.authority.is_some() && base.path.is_empty() {
+        format!("/{}", path)
+    } else {
+        match base.path.rfind('/') {
+            Some(pos) => format!("{}{}", &base.path[..=pos], path),
+            None => path.to_string(),
+        }
+    }
+}
+
+fn remove_dot_segments(mut input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    while !input.is_empty() {
+        if let Some(rest) = input.strip_prefix("../") {
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix("./") {
+            input = rest;
+        } else if input.starts_with("/./") {
+            input = &input[2..];
+        } else if input == "/." {
+            input = "/";
+        } else if input.starts_with("/../") || input == "/.." {
+            input = if input == "/.." { "/" } else { &input[3..] };
+            let last = output.rfind('/').unwrap_or(0);
+            output.truncate(last);
+        } else if input == "." || input == ".." {
+            input = "";
+        } else {
+            let start = usize::from(input.starts_with('/'));
+            let end = input[start..]
+                .find('/')
+                .map_or(input.len(), |pos| pos + start);
+            output.push_str(&input[..end]);
+            input = &input[end..];
+        }
+    }
+    output
+}
+
+/// Decodes `%XX` escapes, invalid ones are kept as they are.
+pub(crate) fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Serializes `fields` as `application/x-www-form-urlencoded`, see the
+/// URL Standard of the WHATWG.
+pub(crate) fn form_urlencode<K, V>(fields: &[(K, V)]) -> String
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut encoded = String::new();
+    for (name, value) in fields {
+        if !encoded.is_empty() {
+            encoded.push('&');
+        }
+        form_urlencode_into(&mut encoded, name.as_ref());
+        encoded.push('=');
+        form_urlencode_into(&mut encoded, value.as_ref());
+    }
+    encoded
+}
+
+fn form_urlencode_into(encoded: &mut String, input: &str) {
+    for byte in input.bytes() {
+        match byte {
+            b'*' | b'-' | b'.' | b'_' => encoded.push(byte as char),
+            _ if byte.is_ascii_alphanumeric() => encoded.push(byte as char),
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+}
+
+/// Whether both URLs have the same scheme, host and port.
+pub(crate) fn same_origin(a: &str, b: &str) -> bool {
+    match (Parts::parse(a).origin(), Parts::parse(b).origin()) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_reference() {
+        // Examples of RFC 3986, Section 5.4
+        let base = "http://a/b/c/d;p?q";
+        for (reference, target) in [
+            ("g:h", "g:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q#s"),
+            ("g#s", "http://a/b/c/g#s"),
+            (";x", "http://a/b/c/;x"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../g", "http://a/g"),
+            ("../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            ("..g", "http://a/b/c/..g"),
+            ("./../g", "http://a/b/g"),
+            ("g/./h", "http://a/b/c/g/h"),
+            ("g/../h", "http://a/b/c/h"),
+            ("g?y/./x", "http://a/b/c/g?y/./x"),
+        ] {
+            assert_eq!(resolve(base, reference).unwrap(), target, "{}", reference);
+        }
+        assert_eq!(resolve("/relative", "g"), None);
+    }
+
+    #[test]
+    fn origins() {
+        assert!(same_origin("http://a/x", "HTTP://A:80/y"));
+        assert!(same_origin("https://user@a/", "https://a:443/"));
+        assert!(!same_origin("http://a/", "https://a/"));
+        assert!(!same_origin("http://a:8080/", "http://a/"));
+        assert!(!same_origin("http://a/", "http://b/"));
+        assert!(same_origin("http://[::1]:80/", "http://[::1]/"));
+    }
+
+    #[test]
+    fn form_encoding() {
+        assert_eq!(
+            form_urlencode(&[("name", "Jane Doe"), ("q", "a+b&c=d/ä*~")]),
+            "name=Jane+Doe&q=a%2Bb%26c%3Dd%2F%C3%A4*%7E"
+        );
+        assert_eq!(form_urlencode::<&str, &str>(&[]), "");
+    }
+}
@@ -1,12 +1,21 @@
-use std::borrow::Cow;
-use std::collections::HashMap;
+use crate::header::parse_header_line;
+use crate::request::{Body, Request};
+use crate::session::Config;
+use crate::{
+    ErrorKind, HeaderMap, Proxy, ProxyKind, Response, StatusCode, Timeouts, TlsConfig, TlsVersion,
+};
+use std::error::Error as StdError;
 use std::ffi::{CString, OsStr};
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::Read;
 use std::iter::once;
-use std::marker::PhantomData;
 use std::mem;
 use std::os::windows::ffi::OsStrExt;
 use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use winapi::ctypes::c_void;
 use winapi::um::errhandlingapi::GetLastError;
 use winapi::um::libloaderapi::{FreeLibrary, LoadLibraryW};
@@ -17,57 +26,94 @@ use winapi::um::winbase::{
 use winapi::um::winhttp::{
     WinHttpAddRequestHeaders, WinHttpCloseHandle, WinHttpConnect, WinHttpCrackUrl, WinHttpOpen,
     WinHttpOpenRequest, WinHttpQueryDataAvailable, WinHttpQueryHeaders, WinHttpReadData,
-    WinHttpReceiveResponse, WinHttpSendRequest, WinHttpSetStatusCallback, HINTERNET,
-    LPURL_COMPONENTS, URL_COMPONENTS, WINHTTP_CALLBACK_FLAG_ALL_COMPLETIONS,
-    WINHTTP_CALLBACK_FLAG_REDIRECT, WINHTTP_CALLBACK_STATUS_DATA_AVAILABLE,
+    WinHttpReceiveResponse, WinHttpSendRequest, WinHttpSetOption, WinHttpSetStatusCallback,
+    WinHttpSetTimeouts, WinHttpWriteData, HINTERNET, INTERNET_SCHEME_HTTPS, LPURL_COMPONENTS,
+    URL_COMPONENTS, WINHTTP_ASYNC_RESULT, WINHTTP_CALLBACK_FLAG_ALL_COMPLETIONS,
+    WINHTTP_CALLBACK_FLAG_HANDLES, WINHTTP_CALLBACK_FLAG_REDIRECT,
+    WINHTTP_CALLBACK_STATUS_DATA_AVAILABLE, WINHTTP_CALLBACK_STATUS_HANDLE_CLOSING,
     WINHTTP_CALLBACK_STATUS_HEADERS_AVAILABLE, WINHTTP_CALLBACK_STATUS_READ_COMPLETE,
-    WINHTTP_CALLBACK_STATUS_SENDREQUEST_COMPLETE, WINHTTP_FLAG_ASYNC, WINHTTP_FLAG_SECURE,
-    WINHTTP_QUERY_FLAG_NUMBER, WINHTTP_QUERY_RAW_HEADERS_CRLF, WINHTTP_QUERY_STATUS_CODE,
+    WINHTTP_CALLBACK_STATUS_REQUEST_ERROR, WINHTTP_CALLBACK_STATUS_SENDREQUEST_COMPLETE,
+    WINHTTP_FLAG_ASYNC, WINHTTP_FLAG_SECURE, WINHTTP_QUERY_FLAG_NUMBER,
+    WINHTTP_QUERY_RAW_HEADERS_CRLF, WINHTTP_QUERY_STATUS_CODE,
 };
 
 mod punycode;
 
 const WINHTTP_ADDREQ_FLAG_ADD: u32 = 0x20000000;
 const MINUS_ONE: u32 = 0xFFFFFFFF;
+const ERROR_WINHTTP_OPERATION_CANCELLED: u32 = 12017;
+const WINHTTP_OPTION_REDIRECT_POLICY: u32 = 88;
+const WINHTTP_OPTION_REDIRECT_POLICY_NEVER: u32 = 0;
+const WINHTTP_OPTION_PROXY: u32 = 38;
+const WINHTTP_OPTION_DISABLE_FEATURE: u32 = 63;
+const WINHTTP_DISABLE_COOKIES: u32 = 0x1;
+const WINHTTP_OPTION_DECOMPRESSION: u32 = 118;
+const WINHTTP_DECOMPRESSION_FLAG_ALL: u32 = 0x3;
+const WINHTTP_OPTION_PROXY_USERNAME: u32 = 0x1002;
+const WINHTTP_OPTION_PROXY_PASSWORD: u32 = 0x1003;
+const WINHTTP_ACCESS_TYPE_NAMED_PROXY: u32 = 3;
+const WINHTTP_OPTION_SECURITY_FLAGS: u32 = 31;
+const SECURITY_FLAG_IGNORE_UNKNOWN_CA: u32 = 0x100;
+const SECURITY_FLAG_IGNORE_CERT_WRONG_USAGE: u32 = 0x200;
+const SECURITY_FLAG_IGNORE_CERT_CN_INVALID: u32 = 0x1000;
+const SECURITY_FLAG_IGNORE_CERT_DATE_INVALID: u32 = 0x2000;
+const WINHTTP_OPTION_SECURE_PROTOCOLS: u32 = 84;
+const WINHTTP_FLAG_SECURE_PROTOCOL_TLS1: u32 = 0x80;
+const WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_1: u32 = 0x200;
+const WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_2: u32 = 0x800;
+const WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_3: u32 = 0x2000;
+
+/// WINHTTP_PROXY_INFO
+#[repr(C)]
+struct ProxyInfo {
+    access_type: u32,
+    proxy: *mut u16,
+    proxy_bypass: *mut u16,
+}
 
 pub struct Session {
     session: HINTERNET,
+    tls: Tls,
 }
 
 pub struct AsyncSession {
     session: HINTERNET,
+    tls: Tls,
 }
 
-pub struct RequestBuilder<'s, 'd> {
-    connection: HINTERNET,
-    request: HINTERNET,
-    body: Cow<'d, [u8]>,
-    _session_marker: PhantomData<&'s Session>,
+/// The TLS settings of a session. WinHTTP only sets the protocol versions
+/// on the session handle, the rest is set on each request.
+struct Tls {
+    accept_invalid_certs: bool,
+    /// A setting WinHTTP does not support, reported by every request.
+    unsupported: Option<&'static str>,
+    /// Why the protocol versions could not be set, e.g. TLS 1.3 on older
+    /// versions of Windows.
+    protocols_error: Option<u32>,
 }
 
-pub struct AsyncRequestBuilder<'s> {
+pub struct RequestHandle {
     request: HINTERNET,
-    body: Vec<u8>,
-    _session_marker: PhantomData<&'s Session>,
+    finished: Arc<AtomicBool>,
 }
 
-pub struct Response {
-    body: Vec<u8>,
-    status_code: u32,
-    headers: HashMap<String, String>,
-}
+unsafe impl Send for AsyncSession {}
 
-pub struct Headers<'a> {
-    headers: &'a HashMap<String, String>,
-}
+unsafe impl Sync for AsyncSession {}
+
+unsafe impl Send for RequestHandle {}
+
+unsafe impl Sync for RequestHandle {}
 
 pub enum Error {
     InvalidHeader,
     WinAPI(u32),
+    Body(io::Error),
+    Unsupported(&'static str),
+    /// The status code of the response is not a three-digit number.
+    InvalidStatus(u32),
 }
 
-unsafe impl Send for Response {}
-
 unsafe impl Send for Error {}
 
 fn to_wide_string(string: &str) -> Vec<u16> {
@@ -90,21 +136,153 @@ fn win_result_ptr<T>(ptr: *mut T) -> Result<*mut T, Error> {
     }
 }
 
+impl Tls {
+    unsafe fn new(session: HINTERNET, config: &TlsConfig) -> Tls {
+        let unsupported = if !config.root_certificates.is_empty() {
+            Some("additional root certificates")
+        } else if config.identity.is_some() {
+            Some("client certificates")
+        } else if !config.pinned_public_keys.is_empty() {
+            Some("public key pinning")
+        } else {
+            None
+        };
+        let protocols_error = config.min_version.and_then(|version| {
+            let min = match version {
+                TlsVersion::Tls1_0 => WINHTTP_FLAG_SECURE_PROTOCOL_TLS1,
+                TlsVersion::Tls1_1 => WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_1,
+                TlsVersion::Tls1_2 => WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_2,
+                TlsVersion::Tls1_3 => WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_3,
+            };
+            // The version flags are ascending, so all from min upwards
+            let protocols = [
+                WINHTTP_FLAG_SECURE_PROTOCOL_TLS1,
+                WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_1,
+                WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_2,
+                WINHTTP_FLAG_SECURE_PROTOCOL_TLS1_3,
+            ]
+            .iter()
+            .filter(|&&protocol| protocol >= min)
+            .fold(0, |protocols, protocol| protocols | protocol);
+            match win_result_bool(WinHttpSetOption(
+                session,
+                WINHTTP_OPTION_SECURE_PROTOCOLS,
+                &protocols as *const u32 as *mut c_void,
+                mem::size_of::<u32>() as u32,
+            )) {
+                Err(Error::WinAPI(code)) => Some(code),
+                _ => None,
+            }
+        });
+        Tls {
+            accept_invalid_certs: config.accept_invalid_certs,
+            unsupported,
+            protocols_error,
+        }
+    }
+
+    unsafe fn configure(&self, handle: HINTERNET) -> Result<(), Error> {
+        if let Some(feature) = self.unsupported {
+            return Err(Error::Unsupported(feature));
+        }
+        if let Some(code) = self.protocols_error {
+            return Err(Error::WinAPI(code));
+        }
+        if self.accept_invalid_certs {
+            let flags = SECURITY_FLAG_IGNORE_UNKNOWN_CA
+                | SECURITY_FLAG_IGNORE_CERT_WRONG_USAGE
+                | SECURITY_FLAG_IGNORE_CERT_CN_INVALID
+                | SECURITY_FLAG_IGNORE_CERT_DATE_INVALID;
+            win_result_bool(WinHttpSetOption(
+                handle,
+                WINHTTP_OPTION_SECURITY_FLAGS,
+                &flags as *const u32 as *mut c_void,
+                mem::size_of::<u32>() as u32,
+            ))?;
+        }
+        Ok(())
+    }
+}
+
 impl Session {
-    pub fn new() -> Session {
-        let session =
-            win_result_ptr(unsafe { WinHttpOpen(wstrz!("nttp").as_ptr(), 1, null(), null(), 0) })
-                .unwrap();
+    pub fn new(config: &Config) -> Session {
+        let session = win_result_ptr(unsafe {
+            WinHttpOpen(to_wide_string("nttp").as_ptr(), 1, null(), null(), 0)
+        })
+        .unwrap();
+        let tls = unsafe { Tls::new(session, &config.tls) };
+
+        Session { session, tls }
+    }
+
+    pub fn send(&self, request: Request) -> Result<Response, Error> {
+        let (connection, handle) = open_request(self.session, &request, &self.tls)?;
+        let (status_code, headers, body) = unsafe {
+            match request.body {
+                Some(Body::Reader(mut reader, len)) => {
+                    if len.is_none() {
+                        add_header(handle, "Transfer-Encoding", "chunked")?;
+                    }
+                    // The total length is only a hint for WinHTTP, larger
+                    // bodies are still written completely by write_body
+                    let total_len = len.map_or(0, |len| len.min(u32::MAX as u64 - 1) as u32);
+                    win_result_bool(WinHttpSendRequest(
+                        handle,
+                        null(),
+                        0,
+                        null_mut(),
+                        0,
+                        total_len,
+                        0,
+                    ))?;
+                    write_body(handle, &mut reader, len.is_none())?;
+                }
+                body => {
+                    let body = body.as_ref().and_then(Body::as_bytes).unwrap_or(&[]);
+                    win_result_bool(WinHttpSendRequest(
+                        handle,
+                        null(),
+                        0,
+                        body.as_ptr() as *mut c_void,
+                        body.len() as u32,
+                        body.len() as u32,
+                        0,
+                    ))?;
+                }
+            }
+
+            win_result_bool(WinHttpReceiveResponse(handle, null_mut()))?;
+
+            let mut data_avaliable: u32 = 0;
+            win_result_bool(WinHttpQueryDataAvailable(
+                handle,
+                &mut data_avaliable as *mut u32,
+            ))?;
+
+            let mut body = vec![0u8; data_avaliable as usize];
+            let mut data_read: u32 = 0;
+            win_result_bool(WinHttpReadData(
+                handle,
+                body.as_mut_ptr() as *mut c_void,
+                data_avaliable,
+                &mut data_read as *mut u32,
+            ))?;
+
+            let (status_code, headers) = read_headers(handle)?;
+
+            WinHttpCloseHandle(handle);
+            WinHttpCloseHandle(connection);
 
-        Session { session }
+            (status_code, headers, body)
+        };
+
+        Ok(Response::new(status_code, headers, body))
     }
 
-    pub fn request<'s, 'd>(
-        &'s self,
-        method: &str,
-        url: &str,
-    ) -> Result<RequestBuilder<'s, 'd>, Error> {
-        RequestBuilder::new(self.session, method, url)
+    // The body is read completely by send, streaming needs WinHttpReadData calls on demand
+    #[inline]
+    pub fn send_streaming(&self, request: Request) -> Result<Response, Error> {
+        self.send(request)
     }
 }
 
@@ -116,13 +294,15 @@ impl Drop for Session {
     }
 }
 
-fn create_request(
+/// Opens a connection and a request handle for `request`, with its headers
+/// and timeouts set and automatic redirects disabled.
+fn open_request(
     session: HINTERNET,
-    method: &str,
-    url: &str,
+    request: &Request,
+    tls: &Tls,
 ) -> Result<(HINTERNET, HINTERNET), Error> {
-    let url = to_wide_string(url);
-    let method = to_wide_string(method);
+    let url = to_wide_string(&request.url);
+    let method = to_wide_string(request.method.as_str());
     unsafe {
         let mut url_component = URL_COMPONENTS {
             dwStructSize: (mem::size_of::<URL_COMPONENTS>() as u32),
@@ -158,25 +338,113 @@ fn create_request(
             0,
         ))?;
 
-        let request = WinHttpOpenRequest(
+        let flags = if url_component.nScheme == INTERNET_SCHEME_HTTPS {
+            WINHTTP_FLAG_SECURE
+        } else {
+            0
+        };
+        let handle = WinHttpOpenRequest(
             connection,
             method.as_ptr(),
             url_component.lpszUrlPath,
             null(),
             null(),
             null_mut(),
-            0,
+            flags,
         );
 
-        Ok((connection, request))
+        let configured = configure_request(handle, request, tls);
+        if configured.is_err() {
+            WinHttpCloseHandle(handle);
+            WinHttpCloseHandle(connection);
+        }
+        configured.map(|_| (connection, handle))
+    }
+}
+
+unsafe fn configure_request(handle: HINTERNET, request: &Request, tls: &Tls) -> Result<(), Error> {
+    let redirect_policy = WINHTTP_OPTION_REDIRECT_POLICY_NEVER;
+    win_result_bool(WinHttpSetOption(
+        handle,
+        WINHTTP_OPTION_REDIRECT_POLICY,
+        &redirect_policy as *const u32 as *mut c_void,
+        mem::size_of::<u32>() as u32,
+    ))?;
+    // Cookies are only handled by the CookieJar of the session
+    let disabled = WINHTTP_DISABLE_COOKIES;
+    win_result_bool(WinHttpSetOption(
+        handle,
+        WINHTTP_OPTION_DISABLE_FEATURE,
+        &disabled as *const u32 as *mut c_void,
+        mem::size_of::<u32>() as u32,
+    ))?;
+    if let Some(proxy) = request.proxy.for_url(&request.url) {
+        set_proxy(handle, proxy)?;
+    }
+    tls.configure(handle)?;
+    if request.decompress {
+        let flags = WINHTTP_DECOMPRESSION_FLAG_ALL;
+        // Unsupported before Windows 8.1, which then asks for no encoding
+        let _ = WinHttpSetOption(
+            handle,
+            WINHTTP_OPTION_DECOMPRESSION,
+            &flags as *const u32 as *mut c_void,
+            mem::size_of::<u32>() as u32,
+        );
     }
+    for (name, value) in &request.headers {
+        add_header(handle, name, &String::from_utf8_lossy(value.as_bytes()))?;
+    }
+    set_timeouts(handle, &request.timeouts)
+}
+
+/// The session is opened without a proxy, so only a configured one is set.
+unsafe fn set_proxy(handle: HINTERNET, proxy: &Proxy) -> Result<(), Error> {
+    if proxy.kind() != ProxyKind::Http {
+        return Err(Error::Unsupported("a proxy other than HTTP"));
+    }
+    let mut server = to_wide_string(&format!("{}:{}", proxy.host(), proxy.port()));
+    let mut bypass = to_wide_string("");
+    let info = ProxyInfo {
+        access_type: WINHTTP_ACCESS_TYPE_NAMED_PROXY,
+        proxy: server.as_mut_ptr(),
+        proxy_bypass: bypass.as_mut_ptr(),
+    };
+    win_result_bool(WinHttpSetOption(
+        handle,
+        WINHTTP_OPTION_PROXY,
+        &info as *const ProxyInfo as *mut c_void,
+        mem::size_of::<ProxyInfo>() as u32,
+    ))?;
+    if let Some((username, password)) = proxy.credentials() {
+        for (option, value) in [
+            (WINHTTP_OPTION_PROXY_USERNAME, username),
+            (WINHTTP_OPTION_PROXY_PASSWORD, password),
+        ] {
+            let value = to_wide_string(value);
+            win_result_bool(WinHttpSetOption(
+                handle,
+                option,
+                value.as_ptr() as *mut c_void,
+                (value.len() - 1) as u32,
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn add_header(request: HINTERNET, key: &str, value: &str) -> Result<(), Error> {
+    let header = to_wide_string(format!("{}: {}", key, value).as_str());
+    win_result_bool(unsafe {
+        WinHttpAddRequestHeaders(request, header.as_ptr(), MINUS_ONE, WINHTTP_ADDREQ_FLAG_ADD)
+    })
 }
 
 impl AsyncSession {
-    pub fn new() -> AsyncSession {
+    pub fn new(config: &Config) -> AsyncSession {
         let session = win_result_ptr(unsafe {
             WinHttpOpen(
-                wstrz!("nttp").as_ptr(),
+                to_wide_string("nttp").as_ptr(),
                 1,
                 null(),
                 null(),
@@ -184,101 +452,142 @@ impl AsyncSession {
             )
         })
         .unwrap();
+        let tls = unsafe { Tls::new(session, &config.tls) };
 
-        AsyncSession { session }
-    }
-
-    pub fn request<'s>(
-        &'s self,
-        method: &str,
-        url: &str,
-    ) -> Result<AsyncRequestBuilder<'s>, Error> {
-        AsyncRequestBuilder::new(self.session, method, url)
-    }
-}
-
-impl<'s, 'd> RequestBuilder<'s, 'd> {
-    fn new(session: HINTERNET, method: &str, url: &str) -> Result<RequestBuilder<'s, 'd>, Error> {
-        let (connection, request) = create_request(session, method, url)?;
-
-        Ok(RequestBuilder {
-            connection,
-            request,
-            body: Cow::Borrowed(&[]),
-            _session_marker: PhantomData,
-        })
+        AsyncSession { session, tls }
     }
 
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        let header = to_wide_string(format!("{}: {}", key, value).as_str());
-        win_result_bool(unsafe {
-            WinHttpAddRequestHeaders(
-                self.request,
-                header.as_ptr(),
-                MINUS_ONE,
-                WINHTTP_ADDREQ_FLAG_ADD,
-            )
-        })
-        .unwrap();
-        self
-    }
+    pub fn send<T>(&self, mut request: Request<'static>, callback: T) -> RequestHandle
+    where
+        T: FnOnce(Result<Response, Error>) + Send + 'static,
+    {
+        let finished = Arc::new(AtomicBool::new(false));
+
+        // Writing the body asynchronously would need a WRITE_COMPLETE driven
+        // state machine, so a reader is buffered instead
+        let body = match request.body.take() {
+            Some(Body::Reader(mut reader, len)) => {
+                let mut body = Vec::with_capacity(len.unwrap_or(0) as usize);
+                reader
+                    .read_to_end(&mut body)
+                    .map(|_| body)
+                    .map_err(Error::Body)
+            }
+            Some(body) => Ok(body.as_bytes().unwrap_or(&[]).to_vec()),
+            None => Ok(Vec::new()),
+        };
+        let opened = body.and_then(|body| {
+            open_request(self.session, &request, &self.tls).map(|(_, handle)| (handle, body))
+        });
+        let (handle, body) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                finished.store(true, Ordering::SeqCst);
+                callback(Err(err));
+                return RequestHandle {
+                    request: null_mut(),
+                    finished,
+                };
+            }
+        };
 
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        self.body = Cow::Owned(data);
-        self
-    }
+        let exchange = Exchange {
+            callback: Box::new(callback),
+            finished: finished.clone(),
+            request: handle,
+            head: None,
+            request_body: body,
+            response_body: Vec::new(),
+        };
 
-    pub fn body_bytes(mut self, data: &'d [u8]) -> Self {
-        self.body = Cow::Borrowed(data);
-        self
-    }
+        unsafe {
+            WinHttpSetStatusCallback(
+                handle,
+                Some(winhttp_callback),
+                WINHTTP_CALLBACK_FLAG_ALL_COMPLETIONS
+                    | WINHTTP_CALLBACK_FLAG_REDIRECT
+                    | WINHTTP_CALLBACK_FLAG_HANDLES,
+                0,
+            );
 
-    pub fn send(mut self) -> Result<Response, Error> {
-        let (status_code, headers, body) = unsafe {
             win_result_bool(WinHttpSendRequest(
-                self.request,
+                handle,
                 null(),
                 0,
-                self.body.as_ptr() as *mut c_void,
-                self.body.len() as u32,
-                self.body.len() as u32,
-                0,
-            ))?;
-
-            win_result_bool(WinHttpReceiveResponse(self.request, null_mut()))?;
-
-            let mut data_avaliable: u32 = 0;
-            win_result_bool(WinHttpQueryDataAvailable(
-                self.request,
-                &mut data_avaliable as *mut u32,
-            ))?;
-
-            let mut body = vec![0u8; data_avaliable as usize];
-            let mut data_read: u32 = 0;
-            win_result_bool(WinHttpReadData(
-                self.request,
-                body.as_mut_ptr() as *mut c_void,
-                data_avaliable,
-                &mut data_read as *mut u32,
-            ))?;
-
-            let (status_code, headers) = read_headers(self.request)?;
+                exchange.request_body.as_ptr() as *mut c_void,
+                exchange.request_body.len() as u32,
+                exchange.request_body.len() as u32,
+                Box::into_raw(Box::new(exchange)) as usize,
+            ))
+            .unwrap();
+        };
+        RequestHandle {
+            request: handle,
+            finished,
+        }
+    }
+}
 
-            WinHttpCloseHandle(self.request);
-            WinHttpCloseHandle(self.connection);
+/// WinHTTP has no limit for the whole request, so it caps the others.
+fn set_timeouts(request: HINTERNET, timeouts: &Timeouts) -> Result<(), Error> {
+    if *timeouts == Timeouts::default() {
+        return Ok(());
+    }
+    let millis = |timeout: Option<Duration>, default: i32| match timeout
+        .into_iter()
+        .chain(timeouts.request)
+        .min()
+    {
+        Some(timeout) => timeout.as_millis().min(i32::MAX as u128) as i32,
+        None => default,
+    };
+    // Defaults as documented for WinHttpSetTimeouts
+    let connect = millis(timeouts.connect, 60_000);
+    let idle = millis(timeouts.idle, 30_000);
+    win_result_bool(unsafe { WinHttpSetTimeouts(request, connect, connect, idle, idle) })
+}
 
-            (status_code, headers, body)
+/// Writes the body from `reader`, using chunked framing if its length is unknown.
+unsafe fn write_body(
+    request: HINTERNET,
+    reader: &mut dyn Read,
+    chunked: bool,
+) -> Result<(), Error> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Error::Body(err)),
         };
+        if chunked {
+            write_data(request, format!("{:X}\r\n", read).as_bytes())?;
+            write_data(request, &buffer[..read])?;
+            write_data(request, b"\r\n")?;
+        } else {
+            write_data(request, &buffer[..read])?;
+        }
+        if read == 0 {
+            return Ok(());
+        }
+    }
+}
 
-        Ok(Response {
-            body,
-            status_code,
-            headers,
-        })
+unsafe fn write_data(request: HINTERNET, mut data: &[u8]) -> Result<(), Error> {
+    while !data.is_empty() {
+        let mut written: u32 = 0;
+        win_result_bool(WinHttpWriteData(
+            request,
+            data.as_ptr() as *const c_void,
+            data.len() as u32,
+            &mut written as *mut u32,
+        ))?;
+        data = &data[written as usize..];
     }
+    Ok(())
 }
 
-fn read_headers(request: HINTERNET) -> Result<(u32, HashMap<String, String>), Error> {
+fn read_headers(request: HINTERNET) -> Result<(StatusCode, HeaderMap), Error> {
     let (status_code, headers_raw) = unsafe {
         let mut status_code: u32 = 0;
         let i32_size: u32 = 4;
@@ -314,209 +623,181 @@ fn read_headers(request: HINTERNET) -> Result<(u32, HashMap<String, String>), Er
         (status_code, headers_raw)
     };
 
-    let mut headers = HashMap::new();
+    let status_code = StatusCode::from_raw(status_code).ok_or(Error::InvalidStatus(status_code))?;
+    let mut headers = HeaderMap::new();
 
     for header in String::from_utf16(&headers_raw[..])
         .map_err(|_| Error::InvalidHeader)?
         .lines()
         .skip(1)
-        .filter(|x| !x.is_empty())
+        .filter(|x| !x.is_empty() && *x != "\0")
     {
-        if header != "\0" {
-            if let Some(seperator_pos) = header.find(':') {
-                let (key, value) = header.split_at(seperator_pos);
-                // Remove ": "
-                let value = value.split_at(2).1;
-                // Remove "\r\n"
-                let value = value.split_at(value.len()).0;
-
-                headers.insert(key.to_string(), value.to_string());
-            } else {
-                return Err(Error::InvalidHeader);
-            }
+        if !parse_header_line(header.as_bytes(), &mut headers) {
+            return Err(Error::InvalidHeader);
         }
     }
 
     Ok((status_code, headers))
 }
 
-impl<'s> AsyncRequestBuilder<'s> {
-    fn new(session: HINTERNET, method: &str, url: &str) -> Result<AsyncRequestBuilder<'s>, Error> {
-        let (_, request) = create_request(session, method, url)?;
-
-        Ok(AsyncRequestBuilder {
-            request,
-            body: vec![],
-            _session_marker: PhantomData,
-        })
-    }
-
-    pub fn header(mut self, key: &str, value: &str) -> Self {
-        let header = to_wide_string(format!("{}: {}", key, value).as_str());
-        win_result_bool(unsafe {
-            WinHttpAddRequestHeaders(
-                self.request,
-                header.as_ptr(),
-                MINUS_ONE,
-                WINHTTP_ADDREQ_FLAG_ADD,
-            )
-        })
-        .unwrap();
-        self
-    }
-
-    pub fn body_vec(mut self, data: Vec<u8>) -> Self {
-        self.body = data;
-        self
-    }
-
-    unsafe extern "system" fn winhttp_callback<T>(
-        connection: HINTERNET,
-        context: usize,
-        status: u32,
-        info: *mut c_void,
-        _info_len: u32,
-    ) {
-        let mut exchange = Box::from_raw(context as *mut Exchange);
-
-        match status {
-            WINHTTP_CALLBACK_STATUS_SENDREQUEST_COMPLETE => {
-                win_result_bool(WinHttpReceiveResponse(exchange.request, null_mut())).unwrap();
-                mem::forget(exchange);
+unsafe extern "system" fn winhttp_callback(
+    connection: HINTERNET,
+    context: usize,
+    status: u32,
+    info: *mut c_void,
+    _info_len: u32,
+) {
+    let mut exchange = Box::from_raw(context as *mut Exchange);
+
+    match status {
+        WINHTTP_CALLBACK_STATUS_SENDREQUEST_COMPLETE => {
+            match win_result_bool(WinHttpReceiveResponse(exchange.request, null_mut())) {
+                Ok(()) => mem::forget(exchange),
+                Err(err) => fail(connection, exchange, err),
             }
-            WINHTTP_CALLBACK_STATUS_HEADERS_AVAILABLE => {
-                let (status_code, headers) = read_headers(exchange.request).unwrap();
-                exchange.status_code = status_code;
-                exchange.headers = Some(headers);
-                win_result_bool(WinHttpQueryDataAvailable(exchange.request, null_mut())).unwrap();
-                mem::forget(exchange);
+        }
+        WINHTTP_CALLBACK_STATUS_HEADERS_AVAILABLE => {
+            match read_headers(exchange.request) {
+                Ok(head) => exchange.head = Some(head),
+                Err(err) => return fail(connection, exchange, err),
             }
-            WINHTTP_CALLBACK_STATUS_DATA_AVAILABLE => {
-                let available_bytes = *(info as *mut u32);
-                if available_bytes == 0 {
-                    let response = Response {
-                        status_code: exchange.status_code,
-                        headers: exchange.headers.unwrap(),
-                        body: exchange.response_body,
-                    };
-
-                    WinHttpSetStatusCallback(exchange.request, None, 0, 0);
-                    WinHttpCloseHandle(exchange.request);
-                    WinHttpCloseHandle(connection);
-                    (exchange.callback)(Ok(response));
-                } else {
-                    exchange.response_body.reserve(available_bytes as usize);
-
-                    let mut bytes_read: u32 = 0;
-
-                    win_result_bool(WinHttpReadData(
-                        exchange.request,
-                        exchange
-                            .response_body
-                            .as_ptr()
-                            .offset(exchange.response_body.len() as isize)
-                            as *mut c_void,
-                        available_bytes,
-                        &mut bytes_read as *mut u32,
-                    ))
-                    .unwrap();
-
-                    let response_body = Vec::from_raw_parts(
-                        exchange.response_body.as_mut_ptr(),
-                        exchange.response_body.len() + bytes_read as usize,
-                        exchange.response_body.capacity(),
-                    );
-
-                    mem::forget(exchange.response_body);
-                    exchange.response_body = response_body;
+            match win_result_bool(WinHttpQueryDataAvailable(exchange.request, null_mut())) {
+                Ok(()) => mem::forget(exchange),
+                Err(err) => fail(connection, exchange, err),
+            }
+        }
+        WINHTTP_CALLBACK_STATUS_DATA_AVAILABLE => {
+            let available_bytes = *(info as *mut u32);
+            if available_bytes == 0 {
+                if exchange.finished.swap(true, Ordering::SeqCst) {
+                    // Cancelled meanwhile, reported once the handle closes
                     mem::forget(exchange);
+                    return;
                 }
-            }
+                let (status_code, headers) = exchange.head.unwrap();
+                let response = Response::new(status_code, headers, exchange.response_body);
 
-            WINHTTP_CALLBACK_STATUS_READ_COMPLETE => {
-                win_result_bool(WinHttpQueryDataAvailable(exchange.request, null_mut())).unwrap();
+                WinHttpSetStatusCallback(exchange.request, None, 0, 0);
+                WinHttpCloseHandle(exchange.request);
+                WinHttpCloseHandle(connection);
+                (exchange.callback)(Ok(response));
+            } else {
+                exchange.response_body.reserve(available_bytes as usize);
+
+                let mut bytes_read: u32 = 0;
+
+                let read = win_result_bool(WinHttpReadData(
+                    exchange.request,
+                    exchange
+                        .response_body
+                        .as_ptr()
+                        .add(exchange.response_body.len()) as *mut c_void,
+                    available_bytes,
+                    &mut bytes_read as *mut u32,
+                ));
+                if let Err(err) = read {
+                    return fail(connection, exchange, err);
+                }
+
+                let response_body = Vec::from_raw_parts(
+                    exchange.response_body.as_mut_ptr(),
+                    exchange.response_body.len() + bytes_read as usize,
+                    exchange.response_body.capacity(),
+                );
+
+                mem::forget(exchange.response_body);
+                exchange.response_body = response_body;
                 mem::forget(exchange);
             }
-            _ => {
-                mem::forget(exchange);
+        }
+
+        WINHTTP_CALLBACK_STATUS_HANDLE_CLOSING => {
+            // Only reached by RequestHandle::cancel, a completed exchange
+            // removes the status callback before closing the handle
+            WinHttpCloseHandle(connection);
+            (exchange.callback)(Err(Error::WinAPI(ERROR_WINHTTP_OPERATION_CANCELLED)));
+        }
+        WINHTTP_CALLBACK_STATUS_READ_COMPLETE => {
+            match win_result_bool(WinHttpQueryDataAvailable(exchange.request, null_mut())) {
+                Ok(()) => mem::forget(exchange),
+                Err(err) => fail(connection, exchange, err),
             }
         }
+        WINHTTP_CALLBACK_STATUS_REQUEST_ERROR => {
+            // e.g. a timeout or a failed connection
+            let result = &*(info as *const WINHTTP_ASYNC_RESULT);
+            fail(connection, exchange, Error::WinAPI(result.dwError));
+        }
+        _ => {
+            mem::forget(exchange);
+        }
     }
+}
 
-    pub fn send<T>(mut self, callback: T)
-    where
-        T: Fn(Result<Response, Error>) + Send + 'static,
-    {
-        let exchange = Exchange {
-            callback: Box::new(callback),
-            request: self.request,
-            status_code: 0,
-            headers: None,
-            request_body: self.body,
-            response_body: Vec::new(),
-        };
-
-        unsafe {
-            WinHttpSetStatusCallback(
-                self.request,
-                Some(AsyncRequestBuilder::winhttp_callback::<T>),
-                WINHTTP_CALLBACK_FLAG_ALL_COMPLETIONS | WINHTTP_CALLBACK_FLAG_REDIRECT,
-                0,
-            );
+/// Completes `exchange` with `err`, unless it was cancelled meanwhile.
+unsafe fn fail(connection: HINTERNET, exchange: Box<Exchange>, err: Error) {
+    if exchange.finished.swap(true, Ordering::SeqCst) {
+        // Reported once the handle closes
+        mem::forget(exchange);
+        return;
+    }
+    WinHttpSetStatusCallback(exchange.request, None, 0, 0);
+    WinHttpCloseHandle(exchange.request);
+    WinHttpCloseHandle(connection);
+    (exchange.callback)(Err(err));
+}
 
-            win_result_bool(WinHttpSendRequest(
-                self.request,
-                null(),
-                0,
-                exchange.request_body.as_ptr() as *mut c_void,
-                exchange.request_body.len() as u32,
-                exchange.request_body.len() as u32,
-                Box::into_raw(Box::new(exchange)) as usize,
-            ))
-            .unwrap();
-        };
+impl RequestHandle {
+    pub fn cancel(&self) {
+        if !self.finished.swap(true, Ordering::SeqCst) {
+            unsafe {
+                WinHttpCloseHandle(self.request);
+            }
+        }
     }
 }
 
 struct Exchange {
-    callback: Box<Fn(Result<Response, Error>) + Send + 'static>,
+    callback: Box<dyn FnOnce(Result<Response, Error>) + Send + 'static>,
+    finished: Arc<AtomicBool>,
     request: HINTERNET,
-    status_code: u32,
-    headers: Option<HashMap<String, String>>,
+    /// The status code and header fields, once received.
+    head: Option<(StatusCode, HeaderMap)>,
     request_body: Vec<u8>,
     response_body: Vec<u8>,
 }
 
-impl Response {
-    pub fn status_code(&self) -> u32 {
-        self.status_code
-    }
-
-    pub fn body(&self) -> &[u8] {
-        &self.body
-    }
-
-    pub fn headers(&self) -> Headers {
-        Headers {
-            headers: &self.headers,
+impl Error {
+    pub(crate) fn kind(&self) -> ErrorKind {
+        match self {
+            // See winhttp.h
+            Error::WinAPI(code) => match code {
+                12002 => ErrorKind::Timeout,
+                12005 | 12006 => ErrorKind::InvalidUrl,
+                12007 => ErrorKind::Dns,
+                12029 => ErrorKind::Connect,
+                12017 => ErrorKind::Aborted,
+                12030 => ErrorKind::Io,
+                12156 => ErrorKind::TooManyRedirects,
+                12037 | 12038 | 12044 | 12045 | 12157 | 12169 | 12175 => ErrorKind::Tls,
+                12150 | 12152 => ErrorKind::Protocol,
+                _ => ErrorKind::Other,
+            },
+            Error::InvalidHeader | Error::InvalidStatus(_) => ErrorKind::Protocol,
+            Error::Unsupported(_) => ErrorKind::Other,
+            Error::Body(_) => ErrorKind::BodyRead,
         }
     }
 }
 
-impl<'a> Headers<'a> {
-    pub fn list(&self) -> Vec<&str> {
-        self.headers
-            .keys()
-            .into_iter()
-            .map(|x| x.as_str())
-            .collect()
-    }
-
-    pub fn get(&self, key: &str) -> Option<&str> {
-        self.headers.get(key).map(|x| x.as_str())
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        Debug::fmt(self, f)
     }
 }
 
+impl StdError for Error {}
+
 impl Debug for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
         match self {
@@ -525,7 +806,7 @@ impl Debug for Error {
 
                 let error_from_winhttp = *code >= 12001 && *code <= 12156; // 12001 to 12156 are WinHTTP errors
                 let dll = if error_from_winhttp {
-                    unsafe { LoadLibraryW(wstrz!("wininet.dll").as_ptr()) }
+                    unsafe { LoadLibraryW(to_wide_string("wininet.dll").as_ptr()) }
                 } else {
                     null_mut()
                 };
@@ -564,6 +845,9 @@ impl Debug for Error {
             }
 
             Error::InvalidHeader => f.write_str("Received Header had invalid format"),
+            Error::InvalidStatus(code) => write!(f, "invalid HTTP status code {}", code),
+            Error::Unsupported(feature) => write!(f, "{} is not supported by WinHTTP", feature),
+            Error::Body(err) => Debug::fmt(err, f),
         }
     }
 }